@@ -5,12 +5,20 @@ use bevy_renet::{RenetClientPlugin, run_if_client_connected};
 use renet::{
     ClientAuthentication, NETCODE_USER_DATA_BYTES, RenetClient, RenetConnectionConfig, RenetError,
 };
+use serde::{Deserialize, Serialize};
 
-use store::{EndGameReason, GameEvent, GameState};
+use store::{EndGameReason, GameEvent, GameState, ServerMessage};
 
 // This id needs to be the same that the server is using
 const PROTOCOL_ID: u64 = 1208;
 
+// Where the audio settings are persisted between sessions
+const SETTINGS_PATH: &str = "settings.json";
+
+// The board is drawn as a 480x480 square just below the ui, no matter how many tiles it has
+const BOARD_SIZE_PX: f32 = 480.0;
+const BOARD_OFFSET_Y: f32 = -30.0;
+
 fn main() {
     // Get username from stdin args
     let args = std::env::args().collect::<Vec<String>>();
@@ -35,7 +43,9 @@ fn main() {
         )
         // Add our game state and register GameEvent as a bevy event
         .insert_resource(GameState::default())
+        .insert_resource(Prediction(None))
         .add_event::<GameEvent>()
+        .add_event::<RejectedEvent>()
         // Add setup function to spawn UI and board graphics
         .add_startup_system(setup)
         // Add systems for playing TicTacTussle
@@ -43,7 +53,16 @@ fn main() {
         .add_system(update_waiting_text)
         .add_system(update_in_game_ui)
         .add_system(update_board)
+        .add_system(update_sub_boards)
         .add_system(input)
+        // Add audio cues and the options overlay for changing the volume
+        .insert_resource(AudioSettings::load())
+        .insert_resource(OptionsOpen(false))
+        .add_startup_system(setup_audio)
+        .add_system(play_sounds)
+        .add_system(toggle_options_overlay)
+        .add_system(change_audio_settings)
+        .add_system(update_options_text)
         // Finally we run the thing!
         .run();
 }
@@ -57,48 +76,98 @@ type TileIndex = usize;
 #[derive(Component)]
 struct HoverDot(pub TileIndex);
 
+/// Marks the grid lines and hover dots, which are rebuilt whenever the size of the board changes
+#[derive(Component)]
+struct BoardGraphics;
+
+/// Highlights the sub-board the active player has to play in, in ultimate mode
+#[derive(Component)]
+struct ActiveSubBoardHighlight;
+
+/// The large piece covering a sub-board that has been won, in ultimate mode
+#[derive(Component)]
+struct WonSubBoard(pub usize);
+
 #[derive(Component)]
 struct WaitingText;
 
 #[derive(Component)]
 struct PlayerHandle(pub u64);
 
-////////// SETUP //////////
-fn setup(mut commands: Commands, asset_server: Res<AssetServer>) {
-    commands.spawn_bundle(Camera2dBundle::default());
+/// Marks a tile sprite that has been placed locally but not yet confirmed by the server
+#[derive(Component)]
+struct PredictedTile;
 
-    // Spawn board background
-    commands.spawn_bundle(SpriteBundle {
-        transform: Transform::from_xyz(0.0, -30.0, 0.0),
-        sprite: Sprite {
-            custom_size: Some(Vec2::new(480.0, 480.0)),
-            ..default()
-        },
-        texture: asset_server.load("background.png").into(),
-        ..default()
-    });
+#[derive(Component)]
+struct OptionsOverlay;
 
-    // Spawn a dot in each tile for hover effect
-    for x in 0..3 {
-        for y in 0..3 {
-            commands
-                .spawn_bundle(SpriteBundle {
-                    transform: Transform::from_xyz(
-                        160.0 * (x as f32 - 1.0),
-                        -30.0 + 160.0 * (y as f32 - 1.0),
-                        0.0,
-                    ),
-                    sprite: Sprite {
-                        color: Color::rgba(1.0, 1.0, 1.0, 0.0),
-                        custom_size: Some(Vec2::new(160.0, 160.0)),
-                        ..default()
-                    },
-                    texture: asset_server.load("dot.png").into(),
-                    ..default()
-                })
-                .insert(HoverDot(x + y * 3));
+#[derive(Component)]
+struct OptionsText;
+
+////////// RESOURCES //////////
+/// Handles to every sound effect the client can play
+struct Sounds {
+    place: Handle<AudioSource>,
+    opponent_place: Handle<AudioSource>,
+    win: Handle<AudioSource>,
+    loss: Handle<AudioSource>,
+    disconnect: Handle<AudioSource>,
+}
+
+/// Audio settings that are saved to disk whenever they change
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+struct AudioSettings {
+    master_volume: f32,
+    muted: bool,
+}
+
+impl Default for AudioSettings {
+    fn default() -> Self {
+        Self {
+            master_volume: 0.8,
+            muted: false,
+        }
+    }
+}
+
+impl AudioSettings {
+    /// Loads the settings from disk, falling back to the defaults if there are none yet
+    fn load() -> Self {
+        std::fs::read_to_string(SETTINGS_PATH)
+            .ok()
+            .and_then(|contents| serde_json::from_str(&contents).ok())
+            .unwrap_or_default()
+    }
+
+    fn save(&self) {
+        let contents = serde_json::to_string_pretty(self).unwrap();
+        if let Err(err) = std::fs::write(SETTINGS_PATH, contents) {
+            warn!("Could not save audio settings: {}", err);
         }
     }
+}
+
+/// Whether the options overlay is currently shown
+struct OptionsOpen(bool);
+
+/// A move we have shown locally while waiting for the server to either echo it back or reject it
+struct PendingPlacement {
+    event: GameEvent,
+    sprite: Entity,
+}
+
+/// The move we are currently predicting, if any. We only ever have a single move in flight.
+struct Prediction(Option<PendingPlacement>);
+
+/// An event we sent that the server refused
+struct RejectedEvent(GameEvent);
+
+////////// SETUP //////////
+fn setup(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands.spawn_bundle(Camera2dBundle::default());
+
+    // Spawn the board, it will be rebuilt if more players join and the board grows
+    spawn_board(&mut commands, &asset_server, &GameState::default());
 
     // Spawn pregame ui
     commands
@@ -134,24 +203,156 @@ fn setup(mut commands: Commands, asset_server: Res<AssetServer>) {
         });
 }
 
+fn spawn_board(commands: &mut Commands, asset_server: &AssetServer, game_state: &GameState) {
+    let grid_size = game_state.grid_size();
+    let tile_size = BOARD_SIZE_PX / grid_size as f32;
+
+    // Spawn the lines between the tiles. In ultimate mode the lines between sub-boards are thicker than the rest.
+    for i in 1..grid_size {
+        let offset = tile_size * i as f32 - BOARD_SIZE_PX / 2.0;
+        let is_sub_board_edge = game_state.mode == store::GameMode::Classic || i % game_state.board_size == 0;
+        let width = if is_sub_board_edge { 5.0 } else { 2.0 };
+        let lines = [
+            (Vec3::new(offset, BOARD_OFFSET_Y, 0.0), Vec2::new(width, BOARD_SIZE_PX - 32.0)),
+            (Vec3::new(0.0, BOARD_OFFSET_Y + offset, 0.0), Vec2::new(BOARD_SIZE_PX - 32.0, width)),
+        ];
+        for (translation, size) in lines {
+            commands
+                .spawn_bundle(SpriteBundle {
+                    transform: Transform::from_translation(translation),
+                    sprite: Sprite {
+                        color: Color::hex("665c54").unwrap(),
+                        custom_size: Some(size),
+                        ..default()
+                    },
+                    ..default()
+                })
+                .insert(BoardGraphics);
+        }
+    }
+
+    // Spawn a dot in each tile for hover effect
+    for x in 0..grid_size {
+        for y in 0..grid_size {
+            let at = game_state.tile_index(x, y);
+            commands
+                .spawn_bundle(SpriteBundle {
+                    transform: Transform::from_translation(tile_translation(game_state, at)),
+                    sprite: Sprite {
+                        color: Color::rgba(1.0, 1.0, 1.0, 0.0),
+                        custom_size: Some(Vec2::new(tile_size, tile_size)),
+                        ..default()
+                    },
+                    texture: asset_server.load("dot.png"),
+                    ..default()
+                })
+                .insert(HoverDot(at))
+                .insert(BoardGraphics);
+        }
+    }
+
+    // Spawn the highlight for the active sub-board, it is moved into place once the game is underway
+    if game_state.mode == store::GameMode::Ultimate {
+        let sub_board_size = tile_size * game_state.board_size as f32;
+        commands
+            .spawn_bundle(SpriteBundle {
+                transform: Transform::from_xyz(0.0, BOARD_OFFSET_Y, -0.1),
+                sprite: Sprite {
+                    color: Color::rgba(0.92, 0.86, 0.7, 0.1),
+                    custom_size: Some(Vec2::new(sub_board_size, sub_board_size)),
+                    ..default()
+                },
+                visibility: Visibility { is_visible: false },
+                ..default()
+            })
+            .insert(ActiveSubBoardHighlight)
+            .insert(BoardGraphics);
+    }
+}
+
+fn setup_audio(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands.insert_resource(Sounds {
+        place: asset_server.load("sounds/place.wav"),
+        opponent_place: asset_server.load("sounds/opponent_place.wav"),
+        win: asset_server.load("sounds/win.wav"),
+        loss: asset_server.load("sounds/loss.wav"),
+        disconnect: asset_server.load("sounds/disconnect.wav"),
+    });
+
+    // Spawn the options overlay, hidden until the player opens it
+    commands
+        .spawn_bundle(NodeBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: UiRect {
+                    left: Val::Px(0.0),
+                    top: Val::Px(0.0),
+                    ..default()
+                },
+                size: Size::new(Val::Percent(100.0), Val::Percent(100.0)),
+                align_items: AlignItems::Center,
+                justify_content: JustifyContent::Center,
+                ..default()
+            },
+            color: Color::rgba(0.0, 0.0, 0.0, 0.8).into(),
+            visibility: Visibility { is_visible: false },
+            ..default()
+        })
+        .insert(OptionsOverlay)
+        .with_children(|parent| {
+            parent
+                .spawn_bundle(TextBundle {
+                    visibility: Visibility { is_visible: false },
+                    ..TextBundle::from_section(
+                        "",
+                        TextStyle {
+                            font: asset_server.load("Inconsolata.ttf"),
+                            font_size: 24.0,
+                            color: Color::hex("ebdbb2").unwrap(),
+                        },
+                    )
+                })
+                .insert(OptionsOverlay)
+                .insert(OptionsText);
+        });
+}
+
 ////////// UPDATE SYSTEMS //////////
-fn input(windows: Res<Windows>, input: Res<Input<MouseButton>>, game_state: Res<GameState>, mut hover_dots: Query<(&HoverDot, &mut Sprite)>, mut client: ResMut<RenetClient>, ) {
+#[allow(clippy::too_many_arguments)]
+fn input(
+    mut commands: Commands,
+    windows: Res<Windows>,
+    input: Res<Input<MouseButton>>,
+    game_state: Res<GameState>,
+    options_open: Res<OptionsOpen>,
+    asset_server: Res<AssetServer>,
+    mut prediction: ResMut<Prediction>,
+    mut hover_dots: Query<(&HoverDot, &mut Sprite)>,
+    mut client: ResMut<RenetClient>,
+) {
     // We only want to handle inputs once we are ingame
     if game_state.stage != store::Stage::InGame {
         return;
     }
 
+    // Clicks belong to the options overlay while it is open
+    if options_open.0 {
+        return;
+    }
+
     let window = windows.get_primary().unwrap();
     if let Some(mouse_position) = window.cursor_position() {
         // Determine the index of the tile that the mouse is currently over
-        let x_tile: usize = (mouse_position.x / 160.0).floor() as usize;
-        let y_tile: usize = (mouse_position.y / 160.0).floor() as usize;
-        let tile = x_tile + y_tile * 3;
+        let grid_size = game_state.grid_size();
+        let tile_size = BOARD_SIZE_PX / grid_size as f32;
+        let x_tile: usize = (mouse_position.x / tile_size).floor() as usize;
+        let y_tile: usize = (mouse_position.y / tile_size).floor() as usize;
 
         // If mouse is outside of board we do nothing
-        if 8 < tile {
+        if x_tile >= grid_size || y_tile >= grid_size {
             return;
         }
+        let tile = game_state.tile_index(x_tile, y_tile);
 
         // Toggle hover dots on and off
         for (dot, mut dot_sprite) in hover_dots.iter_mut() {
@@ -164,10 +365,32 @@ fn input(windows: Res<Windows>, input: Res<Input<MouseButton>>, game_state: Res<
 
         // If left mouse button is pressed, send a place tile event to the server
         if input.just_pressed(MouseButton::Left) {
+            // Wait for the server to confirm our last move before making another one
+            if prediction.0.is_some() {
+                return;
+            }
+
             let event = GameEvent::PlaceTile {
                 player_id: client.client_id(),
                 at: tile,
             };
+
+            // Don't bother the server with moves it is going to reject anyway
+            if !game_state.validate(&event) {
+                return;
+            }
+
+            // Show the move right away, but semi-transparent until the server has confirmed it
+            let piece = game_state.get_player_tile(&client.client_id()).unwrap();
+            let sprite = commands
+                .spawn_bundle(tile_sprite(&asset_server, piece, tile, &game_state, 0.5))
+                .insert(PredictedTile)
+                .id();
+            prediction.0 = Some(PendingPlacement {
+                event: event.clone(),
+                sprite,
+            });
+
             client.send_message(0, bincode::serialize(&event).unwrap());
         }
     }
@@ -177,37 +400,156 @@ fn update_board(
     mut commands: Commands,
     game_state: Res<GameState>,
     mut game_events: EventReader<GameEvent>,
+    mut rejected_events: EventReader<RejectedEvent>,
     asset_server: Res<AssetServer>,
+    mut prediction: ResMut<Prediction>,
+    mut predicted_sprites: Query<&mut Sprite, With<PredictedTile>>,
+    board_graphics: Query<Entity, With<BoardGraphics>>,
 ) {
     for event in game_events.iter() {
         match event {
             GameEvent::PlaceTile { player_id, at } => {
-                let x = at % 3;
-                let y = at / 3;
-                let texture =
-                    asset_server.load(match game_state.get_player_tile(player_id).unwrap() {
-                        store::Tile::Tac => "tac.png",
-                        store::Tile::Tic => "tic.png",
-                        store::Tile::Empty => "dot.png", // This should never happen
-                    });
-
-                commands.spawn_bundle(SpriteBundle {
-                    transform: Transform::from_xyz(
-                        160.0 * (x as f32 - 1.0),
-                        -30.0 + 160.0 * (y as f32 - 1.0),
-                        0.0,
-                    ),
-                    sprite: Sprite {
-                        custom_size: Some(Vec2::new(160.0, 160.0)),
-                        ..default()
-                    },
-                    texture: texture.into(),
-                    ..default()
-                });
+                // The server confirmed our prediction, so the predicted sprite becomes the real one
+                let confirms_prediction = prediction
+                    .0
+                    .as_ref()
+                    .is_some_and(|pending| pending.event == *event);
+                if confirms_prediction {
+                    let pending = prediction.0.take().unwrap();
+                    if let Ok(mut sprite) = predicted_sprites.get_mut(pending.sprite) {
+                        sprite.color.set_a(1.0);
+                    }
+                    commands.entity(pending.sprite).remove::<PredictedTile>();
+                    continue;
+                }
+
+                let piece = game_state.get_player_tile(player_id).unwrap();
+                commands.spawn_bundle(tile_sprite(&asset_server, piece, *at, &game_state, 1.0));
+            }
+            GameEvent::BeginGame { .. } => {
+                // The board grows with the amount of players and the mode, so we rebuild it once we know both
+                for entity in board_graphics.iter() {
+                    commands.entity(entity).despawn();
+                }
+                spawn_board(&mut commands, &asset_server, &game_state);
             }
             _ => {}
         }
     }
+
+    // The server answers every move we send, so our prediction only gets rolled back once it tells us it refused it
+    for RejectedEvent(event) in rejected_events.iter() {
+        let rejects_prediction = prediction.0.as_ref().is_some_and(|pending| pending.event == *event);
+        if rejects_prediction {
+            let pending = prediction.0.take().unwrap();
+            trace!("Rolling back predicted move:\n\t{:#?}", pending.event);
+            commands.entity(pending.sprite).despawn();
+        }
+    }
+}
+
+/// Creates the sprite for a piece placed on the tile at the given index
+fn tile_sprite(asset_server: &AssetServer, piece: store::Tile, at: usize, game_state: &GameState, alpha: f32) -> SpriteBundle {
+    let tile_size = BOARD_SIZE_PX / game_state.grid_size() as f32;
+
+    SpriteBundle {
+        transform: Transform::from_translation(tile_translation(game_state, at)),
+        sprite: Sprite {
+            color: Color::rgba(1.0, 1.0, 1.0, alpha),
+            custom_size: Some(Vec2::new(tile_size, tile_size)),
+            ..default()
+        },
+        texture: asset_server.load(piece_texture(piece)),
+        ..default()
+    }
+}
+
+/// Gets the position of the center of the tile at the given index
+fn tile_translation(game_state: &GameState, at: usize) -> Vec3 {
+    let (x, y) = game_state.tile_position(at);
+    grid_translation(game_state.grid_size(), x as f32, y as f32)
+}
+
+/// Gets the position of a point on the grid, where (0, 0) is the center of the bottom left tile
+fn grid_translation(grid_size: usize, x: f32, y: f32) -> Vec3 {
+    let tile_size = BOARD_SIZE_PX / grid_size as f32;
+    let center = (grid_size as f32 - 1.0) / 2.0;
+    Vec3::new(tile_size * (x - center), BOARD_OFFSET_Y + tile_size * (y - center), 0.0)
+}
+
+fn piece_texture(piece: store::Tile) -> &'static str {
+    match piece {
+        store::Tile::Tac => "tac.png",
+        store::Tile::Tic => "tic.png",
+        store::Tile::Toe => "toe.png",
+        store::Tile::Tussle => "tussle.png",
+        store::Tile::Empty => "dot.png", // This should never happen
+    }
+}
+
+/// Every piece has its own color, which is also used for the name of the player placing it
+fn piece_color(piece: store::Tile) -> Color {
+    match piece {
+        store::Tile::Tic => Color::hex("458488").unwrap(),
+        store::Tile::Tac => Color::hex("d65d0e").unwrap(),
+        store::Tile::Toe => Color::hex("d79921").unwrap(),
+        store::Tile::Tussle => Color::hex("b16286").unwrap(),
+        store::Tile::Empty => Color::hex("ebdbb2").unwrap(),
+    }
+}
+
+/// Moves the highlight to the sub-board the active player has to play in and covers sub-boards that have been won
+fn update_sub_boards(
+    mut commands: Commands,
+    game_state: Res<GameState>,
+    asset_server: Res<AssetServer>,
+    mut highlight: Query<(&mut Transform, &mut Visibility), With<ActiveSubBoardHighlight>>,
+    won_sub_boards: Query<&WonSubBoard>,
+) {
+    if !game_state.is_changed() || game_state.mode != store::GameMode::Ultimate {
+        return;
+    }
+
+    let n = game_state.board_size;
+    // The center of a sub-board on the grid, sub-boards are n tiles wide
+    let sub_board_center = |sub_board: usize| {
+        let x = (sub_board % n * n) as f32 + (n as f32 - 1.0) / 2.0;
+        let y = (sub_board / n * n) as f32 + (n as f32 - 1.0) / 2.0;
+        grid_translation(game_state.grid_size(), x, y)
+    };
+
+    if let Ok((mut transform, mut visibility)) = highlight.get_single_mut() {
+        match game_state.active_sub_board {
+            Some(sub_board) if game_state.stage == store::Stage::InGame => {
+                transform.translation = sub_board_center(sub_board) - Vec3::Z * 0.1;
+                visibility.is_visible = true;
+            }
+            // No highlight means the active player can pick any sub-board
+            _ => visibility.is_visible = false,
+        }
+    }
+
+    for (sub_board, piece) in game_state.board.iter().enumerate() {
+        let is_covered = won_sub_boards.iter().any(|won| won.0 == sub_board);
+        if *piece == store::Tile::Empty || is_covered {
+            continue;
+        }
+
+        let sub_board_size = BOARD_SIZE_PX / n as f32;
+        commands
+            .spawn_bundle(SpriteBundle {
+                transform: Transform::from_translation(sub_board_center(sub_board) + Vec3::Z * 0.1),
+                sprite: Sprite {
+                    color: Color::rgba(1.0, 1.0, 1.0, 0.8),
+                    custom_size: Some(Vec2::new(sub_board_size, sub_board_size)),
+                    ..default()
+                },
+                texture: asset_server.load(piece_texture(*piece)),
+                ..default()
+            })
+            .insert(WonSubBoard(sub_board))
+            .insert(BoardGraphics);
+    }
 }
 
 fn update_waiting_text(mut text_query: Query<&mut Text, With<WaitingText>>, time: Res<Time>) {
@@ -234,16 +576,17 @@ fn change_ui_by_stage(
 
     for event in game_events.iter() {
         match event {
-            GameEvent::BeginGame { goes_first: _ } => {
+            GameEvent::BeginGame { .. } => {
                 // Remove waiting text when game begins
                 ui_root.despawn_descendants();
 
                 // Spawn in game ui
                 ui_root_style.justify_content = JustifyContent::SpaceBetween;
                 ui_root.with_children(|parent| {
-                    for (player_id, player) in game_state.players.iter() {
+                    // Players are listed in the order they take turns
+                    for player_id in game_state.turn_order.iter() {
+                        let player = &game_state.players[player_id];
                         let is_active_player = game_state.active_player_id == *player_id;
-                        let is_tac_player = player.piece == store::Tile::Tac;
 
                         parent
                             .spawn_bundle(TextBundle::from_section(
@@ -254,11 +597,7 @@ fn change_ui_by_stage(
                                     color: if !is_active_player {
                                         Color::hex("ebdbb2").unwrap()
                                     } else {
-                                        if is_tac_player {
-                                            Color::hex("d65d0e").unwrap()
-                                        } else {
-                                            Color::hex("458488").unwrap()
-                                        }
+                                        piece_color(player.piece)
                                     },
                                 },
                             ))
@@ -286,18 +625,25 @@ fn change_ui_by_stage(
                     EndGameReason::PlayerWon { winner } => {
                         ui_root.with_children(|parent| {
                             let winner_player = game_state.players.get(winner).unwrap();
-                            let is_tac_player = winner_player.piece == store::Tile::Tac;
 
                             parent.spawn_bundle(TextBundle::from_section(
                                 format!("{} has won!", winner_player.name.clone()),
                                 TextStyle {
                                     font: asset_server.load("Inconsolata.ttf"),
                                     font_size: 24.0,
-                                    color: if is_tac_player {
-                                        Color::hex("d65d0e").unwrap()
-                                    } else {
-                                        Color::hex("458488").unwrap()
-                                    },
+                                    color: piece_color(winner_player.piece),
+                                },
+                            ));
+                        });
+                    }
+                    EndGameReason::Draw => {
+                        ui_root.with_children(|parent| {
+                            parent.spawn_bundle(TextBundle::from_section(
+                                "It's a draw!",
+                                TextStyle {
+                                    font: asset_server.load("Inconsolata.ttf"),
+                                    font_size: 24.0,
+                                    color: Color::hex("ebdbb2").unwrap(),
                                 },
                             ));
                         });
@@ -322,17 +668,12 @@ fn update_in_game_ui(
             } => {
                 for (handle, mut text) in player_handles.iter_mut() {
                     let is_active_player = game_state.active_player_id == handle.0;
-                    let is_tac_player =
-                        game_state.players.get(&handle.0).unwrap().piece == store::Tile::Tac;
+                    let piece = game_state.players.get(&handle.0).unwrap().piece;
 
                     text.sections[0].style.color = if !is_active_player {
                         Color::hex("ebdbb2").unwrap()
                     } else {
-                        if is_tac_player {
-                            Color::hex("d65d0e").unwrap()
-                        } else {
-                            Color::hex("458488").unwrap()
-                        }
+                        piece_color(piece)
                     };
                 }
             }
@@ -341,6 +682,102 @@ fn update_in_game_ui(
     }
 }
 
+fn play_sounds(
+    client: Res<RenetClient>,
+    audio: Res<Audio>,
+    sounds: Res<Sounds>,
+    audio_settings: Res<AudioSettings>,
+    mut game_events: EventReader<GameEvent>,
+) {
+    for event in game_events.iter() {
+        let sound = match event {
+            GameEvent::PlaceTile { player_id, at: _ } => {
+                if *player_id == client.client_id() {
+                    &sounds.place
+                } else {
+                    &sounds.opponent_place
+                }
+            }
+            GameEvent::EndGame {
+                reason: EndGameReason::PlayerWon { winner },
+            } => {
+                if *winner == client.client_id() {
+                    &sounds.win
+                } else {
+                    &sounds.loss
+                }
+            }
+            GameEvent::PlayerDisconnected { player_id: _ } => &sounds.disconnect,
+            _ => continue,
+        };
+
+        if !audio_settings.muted {
+            audio.play_with_settings(
+                sound.clone(),
+                PlaybackSettings::ONCE.with_volume(audio_settings.master_volume),
+            );
+        }
+    }
+}
+
+fn toggle_options_overlay(
+    keyboard_input: Res<Input<KeyCode>>,
+    mut options_open: ResMut<OptionsOpen>,
+    mut overlay: Query<&mut Visibility, With<OptionsOverlay>>,
+) {
+    if !keyboard_input.just_pressed(KeyCode::Escape) {
+        return;
+    }
+
+    options_open.0 = !options_open.0;
+    for mut visibility in overlay.iter_mut() {
+        visibility.is_visible = options_open.0;
+    }
+}
+
+fn change_audio_settings(
+    keyboard_input: Res<Input<KeyCode>>,
+    options_open: Res<OptionsOpen>,
+    mut audio_settings: ResMut<AudioSettings>,
+) {
+    let mut new_settings = *audio_settings;
+
+    // Muting works everywhere, the volume can only be changed from the overlay
+    if keyboard_input.just_pressed(KeyCode::M) {
+        new_settings.muted = !new_settings.muted;
+    }
+    if options_open.0 {
+        if keyboard_input.just_pressed(KeyCode::Left) {
+            new_settings.master_volume = (new_settings.master_volume - 0.1).max(0.0);
+        }
+        if keyboard_input.just_pressed(KeyCode::Right) {
+            new_settings.master_volume = (new_settings.master_volume + 0.1).min(1.0);
+        }
+    }
+
+    if new_settings != *audio_settings {
+        *audio_settings = new_settings;
+        audio_settings.save();
+    }
+}
+
+fn update_options_text(
+    audio_settings: Res<AudioSettings>,
+    mut options_text: Query<&mut Text, With<OptionsText>>,
+) {
+    if !audio_settings.is_changed() {
+        return;
+    }
+
+    if let Ok(mut text) = options_text.get_single_mut() {
+        text.sections[0].value = format!(
+            "Options\n\nVolume: < {:>3}% >\nSound:  {}\n\n[Left/Right] volume\n[M] mute\n[Esc] close",
+            (audio_settings.master_volume * 100.0).round(),
+            if audio_settings.muted { "muted" } else { "on" }
+        );
+    }
+}
+
 ////////// RENET NETWORKING //////////
 fn new_renet_client(username: &String) -> anyhow::Result<RenetClient> {
     let server_addr = "127.0.0.1:5000".parse()?;
@@ -376,18 +813,23 @@ fn receive_events_from_server(
     mut client: ResMut<RenetClient>,
     mut game_state: ResMut<GameState>,
     mut game_events: EventWriter<GameEvent>,
+    mut rejected_events: EventWriter<RejectedEvent>,
 ) {
     while let Some(message) = client.receive_message(0) {
-        // Whenever the server sends a message we know that it must be a game event
-        let event: GameEvent = bincode::deserialize(&message).unwrap();
-        trace!("{:#?}", event);
+        let message: ServerMessage = bincode::deserialize(&message).unwrap();
+        trace!("{:#?}", message);
 
-        // We trust the server - It's always been good to us!
-        // No need to validate the events it is sending us
-        game_state.consume(&event);
+        match message {
+            ServerMessage::Event(event) => {
+                // We trust the server - It's always been good to us!
+                // No need to validate the events it is sending us
+                game_state.consume(&event);
 
-        // Send the event into the bevy event system so systems can react to it
-        game_events.send(event);
+                // Send the event into the bevy event system so systems can react to it
+                game_events.send(event);
+            }
+            ServerMessage::Rejected(event) => rejected_events.send(RejectedEvent(event)),
+        }
     }
 }
 
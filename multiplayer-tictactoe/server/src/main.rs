@@ -1,4 +1,5 @@
 use log::{info, trace, warn};
+use metrics::{Metrics, MetricsEndpoint, MetricsFile};
 use renet::{
     NETCODE_USER_DATA_BYTES, RenetConnectionConfig, RenetServer, ServerAuthentication, ServerConfig,
     ServerEvent,
@@ -6,7 +7,9 @@ use renet::{
 use std::net::{SocketAddr, UdpSocket};
 use std::thread;
 use std::time::{Duration, Instant, SystemTime};
-use store::EndGameReason;
+use store::{EndGameReason, ServerMessage};
+
+mod metrics;
 
 // TicTacTussle converted to utf-8 codes is 84 105 99 84 97 99 84 117 115 115 108 101
 // If you add those up you get 1208.
@@ -23,20 +26,57 @@ fn name_from_user_data(user_data: &[u8; NETCODE_USER_DATA_BYTES]) -> String {
     String::from_utf8(data).unwrap()
 }
 
+/// Sends a message to a single client, keeping track of the bytes sent
+fn send_message(server: &mut RenetServer, metrics: &mut Metrics, client_id: u64, message: &ServerMessage) {
+    let message = bincode::serialize(message).unwrap();
+    metrics.count_sent(&message, 1);
+    server.send_message(client_id, 0, message);
+}
+
+/// Sends an event to a single client, keeping track of the bytes sent
+fn send_event(server: &mut RenetServer, metrics: &mut Metrics, client_id: u64, event: &store::GameEvent) {
+    send_message(server, metrics, client_id, &ServerMessage::Event(event.clone()));
+}
+
+/// Tells a client the event it sent was refused, so it can roll back its prediction
+fn reject_event(server: &mut RenetServer, metrics: &mut Metrics, client_id: u64, event: &store::GameEvent) {
+    send_message(server, metrics, client_id, &ServerMessage::Rejected(event.clone()));
+}
+
+/// Sends an event to every client, keeping track of the bytes sent
+fn broadcast_event(server: &mut RenetServer, metrics: &mut Metrics, event: &store::GameEvent) {
+    let message = bincode::serialize(&ServerMessage::Event(event.clone())).unwrap();
+    metrics.count_sent(&message, server.clients_id().len());
+    server.broadcast_message(0, message);
+}
+
 fn main() {
     env_logger::init();
 
     let server_addr: SocketAddr = format!("{}:{}", env!("HOST"), env!("PORT"))
         .parse()
         .unwrap();
+
+    // The game begins once this many players have joined, 2 unless PLAYERS says otherwise
+    let player_count = std::env::var("PLAYERS")
+        .ok()
+        .and_then(|players| players.parse::<usize>().ok())
+        .unwrap_or(2)
+        .clamp(2, store::MAX_PLAYERS);
+
+    // MODE=ultimate plays ultimate tic-tac-toe, anything else the classic game
+    let mode = match std::env::var("MODE").as_deref() {
+        Ok("ultimate") => store::GameMode::Ultimate,
+        _ => store::GameMode::Classic,
+    };
     let mut server: RenetServer = RenetServer::new(
         // Pass the current time to renet, so it can use it to order messages
         SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
             .unwrap(),
-        // Pass a server configuration specifying that we want to allow only 2 clients to connect
+        // Pass a server configuration specifying that we want to allow only as many clients as there are players
         // and that we don't want to authenticate them. Everybody is welcome!
-        ServerConfig::new(2, PROTOCOL_ID, server_addr, ServerAuthentication::Unsecure),
+        ServerConfig::new(player_count, PROTOCOL_ID, server_addr, ServerAuthentication::Unsecure),
         // Pass the default connection configuration. This will create a reliable, unreliable and blocking channel.
         // We only actually need the reliable one, but we can just not use the other two.
         RenetConnectionConfig::default(),
@@ -44,7 +84,18 @@ fn main() {
     )
         .unwrap();
 
-    trace!("🕹  TicTacTussle server listening on {}", server_addr);
+    trace!("🕹  TicTacTussle server listening on {} for {} players", server_addr, player_count);
+
+    // Metrics are opt-in: METRICS_FILE appends them as JSON lines, METRICS_ADDR serves them for Prometheus
+    let mut metrics = Metrics::default();
+    let mut metrics_file = std::env::var("METRICS_FILE").ok().map(|path| {
+        MetricsFile::open(&path, Duration::from_secs(10)).unwrap()
+    });
+    let mut metrics_endpoint = std::env::var("METRICS_ADDR").ok().map(|addr| {
+        let addr: SocketAddr = addr.parse().unwrap();
+        trace!("📈 Serving metrics on http://{}/metrics", addr);
+        MetricsEndpoint::bind(addr).unwrap()
+    });
 
     let mut game_state = store::GameState::default();
     let mut last_updated = Instant::now();
@@ -59,13 +110,13 @@ fn main() {
         while let Some(event) = server.get_event() {
             match event {
                 ServerEvent::ClientConnected(id, user_data) => {
-                    // Tell the recently joined player about the other player
+                    // Tell the recently joined player about the other players
                     for (player_id, player) in game_state.players.iter() {
                         let event = store::GameEvent::PlayerJoined {
                             player_id: *player_id,
                             name: player.name.clone(),
                         };
-                        server.send_message(id, 0, bincode::serialize(&event).unwrap());
+                        send_event(&mut server, &mut metrics, id, &event);
                     }
 
                     // Add the new player to the game
@@ -76,14 +127,15 @@ fn main() {
                     game_state.consume(&event);
 
                     // Tell all players that a new player has joined
-                    server.broadcast_message(0, bincode::serialize(&event).unwrap());
+                    broadcast_event(&mut server, &mut metrics, &event);
 
                     info!("Client {} connected.", id);
-                    // In TicTacTussle the game can begin once two players has joined
-                    if game_state.players.len() == 2 {
-                        let event = store::GameEvent::BeginGame { goes_first: id };
+                    // In TicTacTussle the game can begin once all players have joined
+                    if game_state.players.len() == player_count {
+                        let event = store::GameEvent::BeginGame { goes_first: id, mode };
                         game_state.consume(&event);
-                        server.broadcast_message(0, bincode::serialize(&event).unwrap());
+                        broadcast_event(&mut server, &mut metrics, &event);
+                        metrics.games_started += 1;
                         trace!("The game gas begun");
                     }
                 }
@@ -91,15 +143,19 @@ fn main() {
                     // First consume a disconnect event
                     let event = store::GameEvent::PlayerDisconnected { player_id: id };
                     game_state.consume(&event);
-                    server.broadcast_message(0, bincode::serialize(&event).unwrap());
+                    broadcast_event(&mut server, &mut metrics, &event);
+                    metrics.forget_client(id);
                     info!("Client {} disconnected", id);
 
-                    // Then end the game, since tic tac toe can't go on with a single player
+                    // Then end the game, since tic tac toe can't go on with a player missing
                     let event = store::GameEvent::EndGame {
                         reason: EndGameReason::PlayerLeft { player_id: id },
                     };
+                    if game_state.stage == store::Stage::InGame {
+                        metrics.games_finished += 1;
+                    }
                     game_state.consume(&event);
-                    server.broadcast_message(0, bincode::serialize(&event).unwrap());
+                    broadcast_event(&mut server, &mut metrics, &event);
 
                     // NOTE: Since we don't authenticate users we can't do any reconnection attempts.
                     // We simply have no way to know if the next user is the same as the one that disconnected.
@@ -110,26 +166,48 @@ fn main() {
         // Receive GameEvents from clients. Broadcast valid events.
         for client_id in server.clients_id().into_iter() {
             while let Some(message) = server.receive_message(client_id, 0) {
+                metrics.count_received(&message);
                 if let Ok(event) = bincode::deserialize::<store::GameEvent>(&message) {
                     if game_state.validate(&event) {
                         game_state.consume(&event);
                         trace!("Player {} sent:\n\t{:#?}", client_id, event);
-                        server.broadcast_message(0, bincode::serialize(&event).unwrap());
-
-                        // Determine if a player has won the game
-                        if let Some(winner) = game_state.determine_winner() {
-                            let event = store::GameEvent::EndGame {
-                                reason: store::EndGameReason::PlayerWon { winner },
-                            };
-                            server.broadcast_message(0, bincode::serialize(&event).unwrap());
+                        broadcast_event(&mut server, &mut metrics, &event);
+
+                        // Determine if a player has won the game, or if nobody can anymore
+                        let reason = if let Some(winner) = game_state.determine_winner() {
+                            Some(EndGameReason::PlayerWon { winner })
+                        } else if game_state.is_draw() {
+                            Some(EndGameReason::Draw)
+                        } else {
+                            None
+                        };
+                        if let Some(reason) = reason {
+                            let event = store::GameEvent::EndGame { reason };
+                            game_state.consume(&event);
+                            metrics.games_finished += 1;
+                            broadcast_event(&mut server, &mut metrics, &event);
                         }
                     } else {
                         warn!("Player {} sent invalid event:\n\t{:#?}", client_id, event);
+                        metrics.count_invalid_event(client_id);
+                        reject_event(&mut server, &mut metrics, client_id, &event);
                     }
+                } else {
+                    warn!("Player {} sent a message that is not a game event", client_id);
+                    metrics.count_invalid_event(client_id);
                 }
             }
         }
 
+        // Publish the metrics
+        metrics.connected_clients = server.clients_id().len();
+        if let Some(metrics_file) = metrics_file.as_mut() {
+            metrics_file.update(&metrics);
+        }
+        if let Some(metrics_endpoint) = metrics_endpoint.as_mut() {
+            metrics_endpoint.update(&metrics);
+        }
+
         server.send_packets().unwrap();
         thread::sleep(Duration::from_millis(50));
     }
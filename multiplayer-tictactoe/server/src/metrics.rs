@@ -0,0 +1,357 @@
+use log::warn;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Counters and gauges describing what the server has been up to
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct Metrics {
+    pub connected_clients: usize,
+    pub games_started: u64,
+    pub games_finished: u64,
+    pub invalid_events: HashMap<u64, u64>,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
+/// A single line in the metrics file, the metrics along with when they were taken
+#[derive(Serialize)]
+struct MetricsLine<'a> {
+    timestamp: u64,
+    #[serde(flatten)]
+    metrics: &'a Metrics,
+}
+
+impl Metrics {
+    pub fn count_invalid_event(&mut self, client_id: u64) {
+        *self.invalid_events.entry(client_id).or_insert(0) += 1;
+    }
+
+    /// Counts a message received from a client
+    pub fn count_received(&mut self, message: &[u8]) {
+        self.bytes_in += message.len() as u64;
+    }
+
+    /// Counts a message sent to `recipients` clients, a broadcast costs as much as sending it to each of them
+    pub fn count_sent(&mut self, message: &[u8], recipients: usize) {
+        self.bytes_out += (message.len() * recipients) as u64;
+    }
+
+    /// Drops everything kept per client, so the label set doesn't keep growing with every client that ever joined
+    pub fn forget_client(&mut self, client_id: u64) {
+        self.invalid_events.remove(&client_id);
+    }
+
+    /// Formats the metrics as a single line of JSON
+    pub fn to_json_line(&self) -> String {
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        serde_json::to_string(&MetricsLine {
+            timestamp,
+            metrics: self,
+        })
+        .unwrap()
+    }
+
+    /// Formats the metrics in the Prometheus text exposition format
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+            writeln!(out, "# HELP tictactussle_{} {}", name, help).unwrap();
+            writeln!(out, "# TYPE tictactussle_{} {}", name, kind).unwrap();
+            writeln!(out, "tictactussle_{} {}", name, value).unwrap();
+        };
+        metric(
+            "connected_clients",
+            "gauge",
+            "Number of currently connected clients.",
+            self.connected_clients as u64,
+        );
+        metric(
+            "games_started_total",
+            "counter",
+            "Number of games that have begun.",
+            self.games_started,
+        );
+        metric(
+            "games_finished_total",
+            "counter",
+            "Number of games that have ended.",
+            self.games_finished,
+        );
+        metric(
+            "bytes_in_total",
+            "counter",
+            "Bytes of game events received from clients.",
+            self.bytes_in,
+        );
+        metric(
+            "bytes_out_total",
+            "counter",
+            "Bytes of game events sent to clients.",
+            self.bytes_out,
+        );
+
+        writeln!(
+            out,
+            "# HELP tictactussle_invalid_events_total Number of invalid events sent by each client."
+        )
+        .unwrap();
+        writeln!(out, "# TYPE tictactussle_invalid_events_total counter").unwrap();
+        for (client_id, count) in self.invalid_events.iter() {
+            writeln!(
+                out,
+                "tictactussle_invalid_events_total{{client_id=\"{}\"}} {}",
+                client_id, count
+            )
+            .unwrap();
+        }
+
+        out
+    }
+}
+
+/// Appends the metrics to a JSON lines file every `interval`
+pub struct MetricsFile {
+    file: File,
+    interval: Duration,
+    last_written: Instant,
+}
+
+impl MetricsFile {
+    pub fn open(path: &str, interval: Duration) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file,
+            interval,
+            last_written: Instant::now(),
+        })
+    }
+
+    /// Writes the metrics if `interval` has passed since they were last written
+    pub fn update(&mut self, metrics: &Metrics) {
+        if self.last_written.elapsed() < self.interval {
+            return;
+        }
+        self.last_written = Instant::now();
+
+        if let Err(err) = writeln!(self.file, "{}", metrics.to_json_line()) {
+            warn!("Could not write metrics: {}", err);
+        }
+    }
+}
+
+/// Scrapes that take longer than this are dropped
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A connection to the metrics endpoint, read and answered a bit at a time as the socket allows
+struct Scrape {
+    stream: TcpStream,
+    request: Vec<u8>,
+    /// Filled in once the whole request has arrived
+    response: Option<Vec<u8>>,
+    written: usize,
+    started: Instant,
+}
+
+impl Scrape {
+    /// Moves the scrape along as far as it can without blocking, returning whether it's done with
+    fn update(&mut self, metrics: &Metrics) -> io::Result<bool> {
+        if self.response.is_none() {
+            let mut buffer = [0u8; 1024];
+            loop {
+                match self.stream.read(&mut buffer) {
+                    // The client won't send anything more, so answer whatever we got
+                    Ok(0) => break,
+                    Ok(read) => {
+                        self.request.extend_from_slice(&buffer[..read]);
+                        if self.request.windows(4).any(|window| window == b"\r\n\r\n") {
+                            break;
+                        }
+                    }
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                    Err(err) => return Err(err),
+                }
+            }
+
+            // Whatever was requested, the answer is always the metrics
+            let body = metrics.to_prometheus();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            self.response = Some(response.into_bytes());
+        }
+
+        let response = self.response.as_ref().unwrap();
+        while self.written < response.len() {
+            match self.stream.write(&response[self.written..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(written) => self.written += written,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// A tiny HTTP endpoint serving the metrics to anyone who asks, usually Prometheus
+pub struct MetricsEndpoint {
+    listener: TcpListener,
+    scrapes: Vec<Scrape>,
+}
+
+impl MetricsEndpoint {
+    pub fn bind(addr: SocketAddr) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        // The server loop can't wait around for scrapes, so we just poll for them every tick
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            listener,
+            scrapes: Vec::new(),
+        })
+    }
+
+    /// Accepts new requests and moves every pending one along, answering them with the current metrics
+    pub fn update(&mut self, metrics: &Metrics) {
+        while let Ok((stream, _)) = self.listener.accept() {
+            if let Err(err) = stream.set_nonblocking(true) {
+                warn!("Could not serve metrics: {}", err);
+                continue;
+            }
+            self.scrapes.push(Scrape {
+                stream,
+                request: Vec::new(),
+                response: None,
+                written: 0,
+                started: Instant::now(),
+            });
+        }
+
+        self.scrapes.retain_mut(|scrape| match scrape.update(metrics) {
+            Ok(true) => false,
+            Ok(false) => scrape.started.elapsed() < SCRAPE_TIMEOUT,
+            Err(err) => {
+                warn!("Could not serve metrics: {}", err);
+                false
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use store::{GameEvent, ServerMessage};
+
+    fn metrics() -> Metrics {
+        let mut metrics = Metrics {
+            connected_clients: 2,
+            games_started: 3,
+            games_finished: 1,
+            bytes_in: 120,
+            bytes_out: 4096,
+            ..Default::default()
+        };
+        metrics.count_invalid_event(7);
+        metrics.count_invalid_event(7);
+        metrics.count_invalid_event(9);
+        metrics
+    }
+
+    #[test]
+    fn formats_prometheus_metrics() {
+        let exposition = metrics().to_prometheus();
+        let lines: Vec<&str> = exposition.lines().collect();
+
+        for (name, kind, value) in [
+            ("connected_clients", "gauge", 2),
+            ("games_started_total", "counter", 3),
+            ("games_finished_total", "counter", 1),
+            ("bytes_in_total", "counter", 120),
+            ("bytes_out_total", "counter", 4096),
+        ] {
+            let at = lines
+                .iter()
+                .position(|line| line.starts_with(&format!("# HELP tictactussle_{} ", name)))
+                .unwrap_or_else(|| panic!("{} has no help line", name));
+            assert_eq!(lines[at + 1], format!("# TYPE tictactussle_{} {}", name, kind));
+            assert_eq!(lines[at + 2], format!("tictactussle_{} {}", name, value));
+        }
+
+        let at = lines
+            .iter()
+            .position(|line| *line == "# TYPE tictactussle_invalid_events_total counter")
+            .unwrap();
+        assert!(lines[at - 1].starts_with("# HELP tictactussle_invalid_events_total "));
+        let mut samples = lines[at + 1..].to_vec();
+        samples.sort_unstable();
+        assert_eq!(
+            samples,
+            [
+                "tictactussle_invalid_events_total{client_id=\"7\"} 2",
+                "tictactussle_invalid_events_total{client_id=\"9\"} 1",
+            ]
+        );
+        assert!(exposition.ends_with('\n'));
+    }
+
+    #[test]
+    fn formats_json_lines() {
+        let line = metrics().to_json_line();
+        assert!(!line.contains('\n'));
+
+        let json: serde_json::Value = serde_json::from_str(&line).unwrap();
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+        assert!(now - json["timestamp"].as_u64().unwrap() <= 1);
+        assert_eq!(json["connected_clients"], 2);
+        assert_eq!(json["games_started"], 3);
+        assert_eq!(json["games_finished"], 1);
+        assert_eq!(json["bytes_in"], 120);
+        assert_eq!(json["bytes_out"], 4096);
+        assert_eq!(json["invalid_events"]["7"], 2);
+        assert_eq!(json["invalid_events"]["9"], 1);
+    }
+
+    #[test]
+    fn counts_bytes_for_every_recipient() {
+        let mut metrics = Metrics::default();
+        let event = GameEvent::PlaceTile { player_id: 7, at: 4 };
+        let message = bincode::serialize(&ServerMessage::Event(event.clone())).unwrap();
+
+        metrics.count_received(&bincode::serialize(&event).unwrap());
+        assert_eq!(metrics.bytes_in, bincode::serialized_size(&event).unwrap());
+
+        // Sent to a single client, then broadcast to three
+        metrics.count_sent(&message, 1);
+        assert_eq!(metrics.bytes_out, message.len() as u64);
+        metrics.count_sent(&message, 3);
+        assert_eq!(metrics.bytes_out, 4 * message.len() as u64);
+
+        // Nobody to send to, nothing sent
+        metrics.count_sent(&message, 0);
+        assert_eq!(metrics.bytes_out, 4 * message.len() as u64);
+    }
+
+    #[test]
+    fn forgets_clients_that_left() {
+        let mut metrics = metrics();
+        metrics.forget_client(7);
+        assert_eq!(metrics.invalid_events.len(), 1);
+        assert!(!metrics.to_prometheus().contains("client_id=\"7\""));
+        assert!(metrics.to_prometheus().contains("tictactussle_invalid_events_total{client_id=\"9\"} 1"));
+
+        // Forgetting a client twice, or one without any counters, is fine
+        metrics.forget_client(7);
+        metrics.forget_client(42);
+        assert_eq!(metrics.invalid_events.len(), 1);
+    }
+}
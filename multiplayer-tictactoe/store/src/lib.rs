@@ -15,8 +15,21 @@ pub enum Tile {
     Empty,
     Tic,
     Tac,
+    Toe,
+    Tussle,
 }
 
+impl Tile {
+    /// The pieces handed out to players, in the order they join the game
+    pub const PIECES: [Tile; 4] = [Tile::Tic, Tile::Tac, Tile::Toe, Tile::Tussle];
+}
+
+/// The most players a game can have, one for each piece
+pub const MAX_PLAYERS: usize = Tile::PIECES.len();
+
+/// How many tiles in a row a player needs to win, no matter the size of the board
+pub const WIN_LENGTH: usize = 3;
+
 /// The different states a game can be in. (not to be confused with the entire "GameState")
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum Stage {
@@ -25,6 +38,16 @@ pub enum Stage {
     Ended,
 }
 
+/// The rules a game is played by
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum GameMode {
+    /// Plain old tic-tac-toe on a single board
+    Classic,
+    /// Every tile of the board is a board of its own. Winning a sub-board claims its tile on the main board, and where
+    /// you place your piece in a sub-board decides which sub-board the next player has to play in.
+    Ultimate,
+}
+
 // This just makes it easier to dissern between a player id and any ol' u64
 type PlayerId = u64;
 
@@ -32,9 +55,20 @@ type PlayerId = u64;
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct GameState {
     pub stage: Stage,
-    pub board: [Tile; 9],
+    pub mode: GameMode,
+    /// The tiles of the board, row by row starting from the bottom left corner.
+    /// In ultimate mode a tile is claimed by whoever wins the sub-board in its place.
+    pub board: Vec<Tile>,
+    /// The width (and height) of the board. The board grows with the amount of players when the game begins.
+    pub board_size: usize,
+    /// In ultimate mode, the sub-board for every tile of the board. Empty in classic mode.
+    pub sub_boards: Vec<Vec<Tile>>,
+    /// In ultimate mode, the sub-board the active player has to play in. None means they can pick any of them.
+    pub active_sub_board: Option<usize>,
     pub active_player_id: PlayerId,
     pub players: HashMap<PlayerId, Player>,
+    /// The order in which players take turns, which is the order in which they joined
+    pub turn_order: Vec<PlayerId>,
     pub history: Vec<GameEvent>,
 }
 
@@ -42,19 +76,14 @@ impl Default for GameState {
     fn default() -> Self {
         Self {
             stage: Stage::PreGame,
-            board: [
-                Tile::Empty,
-                Tile::Empty,
-                Tile::Empty,
-                Tile::Empty,
-                Tile::Empty,
-                Tile::Empty,
-                Tile::Empty,
-                Tile::Empty,
-                Tile::Empty,
-            ],
+            mode: GameMode::Classic,
+            board: vec![Tile::Empty; 9],
+            board_size: 3,
+            sub_boards: Vec::new(),
+            active_sub_board: None,
             active_player_id: 0,
             players: HashMap::new(),
+            turn_order: Vec::new(),
             history: Vec::new(),
         }
     }
@@ -67,37 +96,46 @@ pub enum EndGameReason {
     // Note that it might make sense to keep playing in some other game (like Team Fight Tactics for instance).
     PlayerLeft { player_id: PlayerId },
     PlayerWon { winner: PlayerId },
+    /// Nobody can place a piece anymore, and nobody has won
+    Draw,
 }
 
 /// An event that progresses the GameGameState forward
 #[derive(Debug, Clone, Serialize, PartialEq, Deserialize)]
 pub enum GameEvent {
-    BeginGame { goes_first: PlayerId },
+    BeginGame { goes_first: PlayerId, mode: GameMode },
     EndGame { reason: EndGameReason },
     PlayerJoined { player_id: PlayerId, name: String },
     PlayerDisconnected { player_id: PlayerId },
     PlaceTile { player_id: PlayerId, at: usize },
 }
 
+/// A message the server sends to the clients
+#[derive(Debug, Clone, Serialize, PartialEq, Deserialize)]
+pub enum ServerMessage {
+    /// An event every client consumes to keep its GameState in sync with the server
+    Event(GameEvent),
+    /// An event a client sent that the server refused, only sent back to that client
+    Rejected(GameEvent),
+}
+
 impl GameState {
     /// Determines whether an event is valid considering the current GameState
     pub fn validate(&self, event: &GameEvent) -> bool {
         use GameEvent::*;
         match event {
-            BeginGame { goes_first } => {
-                let player_is_unknown = self.players.contains_key(goes_first);
+            BeginGame { goes_first, mode: _ } => {
+                let player_is_unknown = !self.players.contains_key(goes_first);
                 if self.stage != Stage::PreGame || player_is_unknown {
                     return false;
                 }
             }
-            EndGame { reason } => match reason {
-                EndGameReason::PlayerWon { winner: _ } => {
-                    if self.stage != Stage::InGame {
-                        return false;
-                    }
+            EndGame { reason } => {
+                let needs_running_game = matches!(reason, EndGameReason::PlayerWon { .. } | EndGameReason::Draw);
+                if needs_running_game && self.stage != Stage::InGame {
+                    return false;
                 }
-                _ => {}
-            },
+            }
             PlayerJoined { player_id, name: _ } => {
                 if self.players.contains_key(player_id) {
                     return false;
@@ -117,11 +155,31 @@ impl GameState {
                     return false;
                 }
 
-                if *at > 8 {
-                    return false;
-                }
-                if self.board[*at] != Tile::Empty {
-                    return false;
+                match self.mode {
+                    GameMode::Classic => {
+                        if *at >= self.board.len() {
+                            return false;
+                        }
+                        if self.board[*at] != Tile::Empty {
+                            return false;
+                        }
+                    }
+                    GameMode::Ultimate => {
+                        let (sub_board, tile) = self.split_index(*at);
+                        if sub_board >= self.sub_boards.len() {
+                            return false;
+                        }
+                        // Won sub-boards are closed for business
+                        if self.board[sub_board] != Tile::Empty {
+                            return false;
+                        }
+                        if self.active_sub_board.is_some_and(|active| active != sub_board) {
+                            return false;
+                        }
+                        if self.sub_boards[sub_board][tile] != Tile::Empty {
+                            return false;
+                        }
+                    }
                 }
             }
         }
@@ -134,37 +192,58 @@ impl GameState {
     pub fn consume(&mut self, valid_event: &GameEvent) {
         use GameEvent::*;
         match valid_event {
-            BeginGame { goes_first } => {
+            BeginGame { goes_first, mode } => {
+                self.mode = *mode;
+                self.board_size = board_size_for(self.players.len());
+                let tiles = self.board_size * self.board_size;
+                self.board = vec![Tile::Empty; tiles];
+                self.sub_boards = match mode {
+                    GameMode::Classic => Vec::new(),
+                    GameMode::Ultimate => vec![vec![Tile::Empty; tiles]; tiles],
+                };
+                self.active_sub_board = None;
                 self.active_player_id = *goes_first;
                 self.stage = Stage::InGame;
             }
             EndGame { reason: _ } => self.stage = Stage::Ended,
             PlayerJoined { player_id, name } => {
+                // Players get the first piece nobody else is using, so the first player gets tic, second gets tac...
+                let piece = *Tile::PIECES
+                    .iter()
+                    .find(|piece| self.players.values().all(|player| player.piece != **piece))
+                    .unwrap();
                 self.players.insert(
                     *player_id,
                     Player {
                         name: name.to_string(),
-                        // First player to join gets tac, second gets tic
-                        piece: if self.players.len() > 0 {
-                            Tile::Tac
-                        } else {
-                            Tile::Tic
-                        },
+                        piece,
                     },
                 );
+                self.turn_order.push(*player_id);
             }
             PlayerDisconnected { player_id } => {
                 self.players.remove(player_id);
+                self.turn_order.retain(|id| id != player_id);
             }
             PlaceTile { player_id, at } => {
                 let piece = self.get_player_tile(player_id).unwrap();
-                self.board[*at] = piece;
-                self.active_player_id = self
-                    .players
-                    .keys()
-                    .find(|id| *id != player_id)
-                    .unwrap()
-                    .clone();
+                match self.mode {
+                    GameMode::Classic => self.board[*at] = piece,
+                    GameMode::Ultimate => {
+                        let (sub_board, tile) = self.split_index(*at);
+                        self.sub_boards[sub_board][tile] = piece;
+                        if let Some(winner) = find_line(&self.sub_boards[sub_board], self.board_size) {
+                            self.board[sub_board] = winner;
+                        }
+
+                        // The next player has to play in the sub-board matching the tile that was just taken,
+                        // unless that sub-board can't be played in anymore
+                        let is_playable = self.board[tile] == Tile::Empty
+                            && self.sub_boards[tile].contains(&Tile::Empty);
+                        self.active_sub_board = if is_playable { Some(tile) } else { None };
+                    }
+                }
+                self.active_player_id = self.next_player(player_id);
             }
         }
 
@@ -180,39 +259,371 @@ impl GameState {
         None
     }
 
-    /// Determines if someone has won the game
+    /// The width (and height) of the grid of tiles players place their pieces on.
+    /// In ultimate mode every tile of the board holds a whole sub-board, so the grid is a lot larger than the board.
+    pub fn grid_size(&self) -> usize {
+        match self.mode {
+            GameMode::Classic => self.board_size,
+            GameMode::Ultimate => self.board_size * self.board_size,
+        }
+    }
+
+    /// Converts a position on the grid, counted from the bottom left corner, into the index used by PlaceTile
+    pub fn tile_index(&self, x: usize, y: usize) -> usize {
+        let n = self.board_size;
+        match self.mode {
+            GameMode::Classic => x + y * n,
+            GameMode::Ultimate => {
+                let sub_board = x / n + (y / n) * n;
+                let tile = x % n + (y % n) * n;
+                sub_board * n * n + tile
+            }
+        }
+    }
+
+    /// Converts the index used by PlaceTile back into a position on the grid
+    pub fn tile_position(&self, at: usize) -> (usize, usize) {
+        let n = self.board_size;
+        match self.mode {
+            GameMode::Classic => (at % n, at / n),
+            GameMode::Ultimate => {
+                let (sub_board, tile) = self.split_index(at);
+                ((sub_board % n) * n + tile % n, (sub_board / n) * n + tile / n)
+            }
+        }
+    }
+
+    /// Splits the index of a tile in ultimate mode into the sub-board and the tile within that sub-board
+    fn split_index(&self, at: usize) -> (usize, usize) {
+        let tiles = self.board_size * self.board_size;
+        (at / tiles, at % tiles)
+    }
+
+    /// Gets the player whose turn it is after the given player
+    pub fn next_player(&self, player_id: &PlayerId) -> PlayerId {
+        let position = self
+            .turn_order
+            .iter()
+            .position(|id| id == player_id)
+            .unwrap();
+        self.turn_order[(position + 1) % self.turn_order.len()]
+    }
+
+    /// Determines if someone has won the game.
+    /// In ultimate mode the board holds the winners of the sub-boards, so the same lines win the whole game.
     pub fn determine_winner(&self) -> Option<PlayerId> {
-        // All the combinations of 3 tiles that wins the game
-        let row1: [usize; 3] = [0, 1, 2];
-        let row2: [usize; 3] = [3, 4, 5];
-        let row3: [usize; 3] = [6, 7, 8];
-        let col1: [usize; 3] = [0, 3, 6];
-        let col2: [usize; 3] = [1, 4, 7];
-        let col3: [usize; 3] = [2, 5, 8];
-        let diag1: [usize; 3] = [0, 4, 8];
-        let diag2: [usize; 3] = [2, 4, 6];
-
-        for arr in [row1, row2, row3, col1, col2, col3, diag1, diag2] {
-            // Read tiles from board
-            let tiles: [Tile; 3] = [self.board[arr[0]], self.board[arr[1]], self.board[arr[2]]];
-            // Determine if tiles are all equal
-            let all_are_the_same = tiles
-                .get(0)
-                .map(|first| tiles.iter().all(|x| x == first))
-                .unwrap_or(true);
-
-            if all_are_the_same {
-                // Determine which of the players won
-                if let Some((winner, _)) = self
-                    .players
-                    .iter()
-                    .find(|(_, player)| player.piece == self.board[arr[0]])
-                {
-                    return Some(*winner);
+        let piece = find_line(&self.board, self.board_size)?;
+
+        // Determine which of the players won
+        self.players
+            .iter()
+            .find(|(_, player)| player.piece == piece)
+            .map(|(winner, _)| *winner)
+    }
+
+    /// Determines if the game is stuck: nobody has won, and there is nowhere left to place a piece.
+    /// In ultimate mode that's when every sub-board has either been won or is full.
+    pub fn is_draw(&self) -> bool {
+        if self.determine_winner().is_some() {
+            return false;
+        }
+
+        match self.mode {
+            GameMode::Classic => !self.board.contains(&Tile::Empty),
+            GameMode::Ultimate => self
+                .board
+                .iter()
+                .zip(self.sub_boards.iter())
+                .all(|(tile, sub_board)| *tile != Tile::Empty || !sub_board.contains(&Tile::Empty)),
+        }
+    }
+}
+
+/// The size of the board for a game with the given amount of players.
+/// Two players play on the classic 3x3 board, every additional player adds a row and a column.
+pub fn board_size_for(player_count: usize) -> usize {
+    player_count.max(2) + 1
+}
+
+/// All the combinations of `WIN_LENGTH` tiles in a row that win the game on a board of the given size
+pub fn winning_lines(board_size: usize) -> Vec<[usize; WIN_LENGTH]> {
+    let mut lines = Vec::new();
+    let size = board_size as isize;
+    let length = WIN_LENGTH as isize;
+
+    // Rows, columns and both diagonals, starting from every tile
+    for (dx, dy) in [(1, 0), (0, 1), (1, 1), (-1, 1)] {
+        for x in 0..size {
+            for y in 0..size {
+                let end_x = x + dx * (length - 1);
+                let end_y = y + dy * (length - 1);
+                if end_x < 0 || end_x >= size || end_y >= size {
+                    continue;
+                }
+
+                let mut line = [0; WIN_LENGTH];
+                for (i, tile) in line.iter_mut().enumerate() {
+                    let i = i as isize;
+                    *tile = ((x + dx * i) + (y + dy * i) * size) as usize;
                 }
+                lines.push(line);
             }
         }
+    }
 
-        None
+    lines
+}
+
+/// Finds a piece that fills `WIN_LENGTH` tiles in a row on a square board, if there is one
+pub fn find_line(board: &[Tile], board_size: usize) -> Option<Tile> {
+    for line in winning_lines(board_size) {
+        // Read tiles from board
+        let first = board[line[0]];
+        // Determine if tiles are all equal
+        let all_are_the_same = line.iter().all(|at| board[*at] == first);
+
+        if all_are_the_same && first != Tile::Empty {
+            return Some(first);
+        }
     }
-}
\ No newline at end of file
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A game that has begun with one player for every id, joined in order
+    fn game(player_ids: &[PlayerId], mode: GameMode) -> GameState {
+        let mut game_state = GameState::default();
+        for player_id in player_ids {
+            game_state.consume(&GameEvent::PlayerJoined { player_id: *player_id, name: format!("Player {}", player_id) });
+        }
+        game_state.consume(&GameEvent::BeginGame { goes_first: player_ids[0], mode });
+        game_state
+    }
+
+    #[test]
+    fn boards_grow_with_the_players() {
+        assert_eq!(board_size_for(1), 3);
+        assert_eq!(board_size_for(2), 3);
+        assert_eq!(board_size_for(3), 4);
+        assert_eq!(board_size_for(4), 5);
+        assert_eq!(game(&[1, 2, 3], GameMode::Classic).board.len(), 16);
+    }
+
+    #[test]
+    fn finds_every_line() {
+        // Rows, columns and both diagonals, for every place a line fits
+        for (board_size, expected) in [(3, 8), (4, 24), (5, 48)] {
+            let lines = winning_lines(board_size);
+            assert_eq!(lines.len(), expected, "{}x{} board", board_size, board_size);
+            for line in lines.iter() {
+                assert!(line.iter().all(|at| *at < board_size * board_size));
+            }
+        }
+
+        let lines = winning_lines(3);
+        for line in [[0, 1, 2], [0, 3, 6], [0, 4, 8], [2, 4, 6]] {
+            assert!(lines.contains(&line), "{:?} is missing", line);
+        }
+        let lines = winning_lines(4);
+        for line in [[1, 2, 3], [7, 11, 15], [1, 6, 11], [3, 6, 9], [7, 10, 13]] {
+            assert!(lines.contains(&line), "{:?} is missing", line);
+        }
+        // Lines don't wrap around the edge of the board
+        assert!(!lines.contains(&[2, 3, 4]));
+        let lines = winning_lines(5);
+        for line in [[2, 8, 14], [4, 8, 12], [10, 16, 22], [14, 18, 22]] {
+            assert!(lines.contains(&line), "{:?} is missing", line);
+        }
+        assert!(!lines.contains(&[3, 4, 5]));
+    }
+
+    #[test]
+    fn finds_lines_on_larger_boards() {
+        let mut board = vec![Tile::Empty; 25];
+        assert_eq!(find_line(&board, 5), None);
+
+        // A diagonal going down to the left, away from the corners
+        for at in [9, 13, 17] {
+            board[at] = Tile::Toe;
+        }
+        assert_eq!(find_line(&board, 5), Some(Tile::Toe));
+
+        let mut board = vec![Tile::Empty; 16];
+        for at in [5, 10, 15] {
+            board[at] = Tile::Tussle;
+        }
+        assert_eq!(find_line(&board, 4), Some(Tile::Tussle));
+        board[10] = Tile::Tac;
+        assert_eq!(find_line(&board, 4), None);
+    }
+
+    #[test]
+    fn turns_go_around_in_join_order() {
+        let mut game_state = game(&[10, 20, 30, 40], GameMode::Classic);
+        assert_eq!(game_state.next_player(&10), 20);
+        assert_eq!(game_state.next_player(&30), 40);
+        assert_eq!(game_state.next_player(&40), 10);
+
+        // Whoever left is skipped
+        game_state.consume(&GameEvent::PlayerDisconnected { player_id: 20 });
+        assert_eq!(game_state.next_player(&10), 30);
+        game_state.consume(&GameEvent::PlayerDisconnected { player_id: 40 });
+        assert_eq!(game_state.next_player(&30), 10);
+    }
+
+    #[test]
+    fn three_players_take_turns_and_win() {
+        let mut game_state = game(&[1, 2, 3], GameMode::Classic);
+        assert_eq!(game_state.get_player_tile(&3), Some(Tile::Toe));
+
+        // Player 3 takes a diagonal while the others play elsewhere
+        for (player_id, at) in [(1, 0), (2, 1), (3, 3), (1, 4), (2, 5), (3, 6), (1, 12), (2, 13), (3, 9)] {
+            let event = GameEvent::PlaceTile { player_id, at };
+            assert!(game_state.validate(&event), "{:?} was rejected", event);
+            assert_eq!(game_state.determine_winner(), None);
+            game_state.consume(&event);
+        }
+        assert_eq!(game_state.determine_winner(), Some(3));
+    }
+
+    /// A full 3x3 board without a line on it
+    const FULL_BOARD: [Tile; 9] = [
+        Tile::Tic, Tile::Tac, Tile::Tic,
+        Tile::Tic, Tile::Tac, Tile::Tac,
+        Tile::Tac, Tile::Tic, Tile::Tic,
+    ];
+
+    /// Places a piece on a tile of a sub-board in ultimate mode, if the move is valid
+    fn place(game_state: &mut GameState, player_id: PlayerId, sub_board: usize, tile: usize) -> bool {
+        let event = GameEvent::PlaceTile { player_id, at: sub_board * 9 + tile };
+        let is_valid = game_state.validate(&event);
+        if is_valid {
+            game_state.consume(&event);
+        }
+        is_valid
+    }
+
+    #[test]
+    fn converts_between_indices_and_positions() {
+        let classic = game(&[1, 2], GameMode::Classic);
+        assert_eq!(classic.grid_size(), 3);
+        assert_eq!(classic.tile_index(2, 1), 5);
+        assert_eq!(classic.tile_position(5), (2, 1));
+
+        let ultimate = game(&[1, 2], GameMode::Ultimate);
+        assert_eq!(ultimate.grid_size(), 9);
+        // The bottom left tile of the middle bottom sub-board
+        assert_eq!(ultimate.tile_index(3, 0), 9);
+        assert_eq!(ultimate.split_index(9), (1, 0));
+        // The top right tile of the middle sub-board
+        assert_eq!(ultimate.tile_index(5, 5), 44);
+        assert_eq!(ultimate.split_index(44), (4, 8));
+        assert_eq!(ultimate.tile_position(80), (8, 8));
+
+        for game_state in [classic, ultimate] {
+            let grid_size = game_state.grid_size();
+            let mut seen = vec![false; grid_size * grid_size];
+            for y in 0..grid_size {
+                for x in 0..grid_size {
+                    let at = game_state.tile_index(x, y);
+                    assert!(!seen[at], "({}, {}) has the same index as another tile", x, y);
+                    seen[at] = true;
+                    assert_eq!(game_state.tile_position(at), (x, y));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn winning_a_sub_board_claims_its_tile() {
+        let mut game_state = game(&[1, 2], GameMode::Ultimate);
+        for (player_id, sub_board, tile) in [(1, 4, 0), (2, 0, 4), (1, 4, 1), (2, 1, 4)] {
+            assert!(place(&mut game_state, player_id, sub_board, tile));
+        }
+        assert_eq!(game_state.board[4], Tile::Empty);
+
+        assert!(place(&mut game_state, 1, 4, 2));
+        assert_eq!(game_state.board[4], Tile::Tic);
+        assert_eq!(game_state.determine_winner(), None);
+    }
+
+    #[test]
+    fn moves_send_the_next_player_to_a_sub_board() {
+        let mut game_state = game(&[1, 2], GameMode::Ultimate);
+        // The first move can go anywhere
+        assert_eq!(game_state.active_sub_board, None);
+        assert!(place(&mut game_state, 1, 6, 2));
+        assert_eq!(game_state.active_sub_board, Some(2));
+
+        assert!(!place(&mut game_state, 2, 6, 3));
+        assert!(place(&mut game_state, 2, 2, 6));
+        assert_eq!(game_state.active_sub_board, Some(6));
+        assert!(!place(&mut game_state, 1, 6, 2), "the tile is taken");
+        assert!(place(&mut game_state, 1, 6, 0));
+    }
+
+    #[test]
+    fn full_or_won_sub_boards_let_the_next_player_pick() {
+        let mut game_state = game(&[1, 2], GameMode::Ultimate);
+        game_state.sub_boards[3] = FULL_BOARD.to_vec();
+        game_state.board[5] = Tile::Tac;
+
+        // Sent to a full sub-board
+        assert!(place(&mut game_state, 1, 0, 3));
+        assert_eq!(game_state.active_sub_board, None);
+        assert!(place(&mut game_state, 2, 8, 0));
+        assert_eq!(game_state.active_sub_board, Some(0));
+
+        // Sent to a won sub-board
+        assert!(place(&mut game_state, 1, 0, 5));
+        assert_eq!(game_state.active_sub_board, None);
+        // Which stays closed, along with the full one
+        assert!(!place(&mut game_state, 2, 5, 0));
+        assert!(!place(&mut game_state, 2, 3, 0));
+        assert!(place(&mut game_state, 2, 7, 7));
+    }
+
+    #[test]
+    fn ultimate_games_without_a_line_are_a_draw() {
+        let mut game_state = game(&[1, 2], GameMode::Ultimate);
+        assert!(!game_state.is_draw());
+
+        // Every sub-board is claimed or full, without a line on the board
+        game_state.board = FULL_BOARD.to_vec();
+        game_state.board[4] = Tile::Empty;
+        assert!(!game_state.is_draw());
+        game_state.sub_boards[4] = FULL_BOARD.to_vec();
+        assert!(game_state.is_draw());
+        assert!(game_state.validate(&GameEvent::EndGame { reason: EndGameReason::Draw }));
+
+        // A line wins, even with nowhere left to play
+        game_state.board[4] = Tile::Tic;
+        game_state.board[5] = Tile::Tic;
+        assert_eq!(game_state.determine_winner(), Some(1));
+        assert!(!game_state.is_draw());
+    }
+
+    #[test]
+    fn full_classic_boards_are_a_draw() {
+        let mut game_state = game(&[1, 2], GameMode::Classic);
+        game_state.board = FULL_BOARD.to_vec();
+        assert!(game_state.is_draw());
+
+        game_state.board[8] = Tile::Empty;
+        assert!(!game_state.is_draw());
+        game_state.consume(&GameEvent::EndGame { reason: EndGameReason::Draw });
+        assert!(!game_state.validate(&GameEvent::EndGame { reason: EndGameReason::Draw }));
+    }
+
+    #[test]
+    fn rejects_moves_out_of_turn() {
+        let game_state = game(&[1, 2, 3], GameMode::Classic);
+        assert!(!game_state.validate(&GameEvent::PlaceTile { player_id: 2, at: 0 }));
+        assert!(!game_state.validate(&GameEvent::PlaceTile { player_id: 1, at: 16 }));
+        assert!(game_state.validate(&GameEvent::PlaceTile { player_id: 1, at: 15 }));
+    }
+}
@@ -0,0 +1,154 @@
+use std::fs;
+
+use anyhow::bail;
+use bevy::prelude::*;
+
+pub const ARENA_PATH: &str = "assets/data/arena.txt";
+/// Width and height of a single tile in world units, the same as a player sprite
+pub const TILE_SIZE: f32 = 32.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tile {
+    Floor,
+    Wall,
+}
+
+/// The map everybody plays on, centered on the origin. Anything outside of it counts as wall.
+///
+/// It's loaded from a text file where every line is a row of tiles, `#` for a wall and `.` for floor.
+/// Client and server load the same file, so movement can be predicted without sending the map around.
+#[derive(Debug, Clone)]
+pub struct Arena {
+    width: i32,
+    height: i32,
+    /// Row by row, starting at the bottom
+    tiles: Vec<Tile>,
+}
+
+impl Arena {
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        Self::parse(&contents)
+    }
+
+    pub fn parse(contents: &str) -> anyhow::Result<Self> {
+        let rows: Vec<&str> = contents.lines().map(str::trim_end).filter(|row| !row.is_empty()).collect();
+        let width = rows.first().map_or(0, |row| row.chars().count());
+        if width == 0 {
+            bail!("The arena is empty");
+        }
+
+        let mut tiles = Vec::with_capacity(width * rows.len());
+        // The file starts with the top row, but the world has y going up
+        for (line, row) in rows.iter().rev().enumerate() {
+            if row.chars().count() != width {
+                bail!("Row {} of the arena is not {} tiles wide", rows.len() - line, width);
+            }
+            for character in row.chars() {
+                tiles.push(match character {
+                    '#' => Tile::Wall,
+                    '.' => Tile::Floor,
+                    _ => bail!("Unknown tile '{}' in row {} of the arena", character, rows.len() - line),
+                });
+            }
+        }
+
+        Ok(Self {
+            width: width as i32,
+            height: rows.len() as i32,
+            tiles,
+        })
+    }
+
+    /// Size of the whole arena in world units
+    pub fn size(&self) -> Vec2 {
+        Vec2::new(self.width as f32, self.height as f32) * TILE_SIZE
+    }
+
+    /// World position of the bottom left corner of the arena
+    fn origin(&self) -> Vec2 {
+        -self.size() / 2.0
+    }
+
+    pub fn tile(&self, x: i32, y: i32) -> Tile {
+        if x < 0 || y < 0 || x >= self.width || y >= self.height {
+            return Tile::Wall;
+        }
+        self.tiles[(y * self.width + x) as usize]
+    }
+
+    /// Every tile along with the world position of its center
+    pub fn tiles(&self) -> impl Iterator<Item = (Vec2, Tile)> + '_ {
+        self.tiles.iter().enumerate().map(|(index, tile)| {
+            let x = index as i32 % self.width;
+            let y = index as i32 / self.width;
+            let center = self.origin() + (Vec2::new(x as f32, y as f32) + 0.5) * TILE_SIZE;
+            (center, *tile)
+        })
+    }
+
+    /// The range of tiles, inclusive, covered by a square of `half_size` around `position`
+    fn tile_range(&self, position: Vec2, half_size: f32) -> (IVec2, IVec2) {
+        let min = ((position - half_size - self.origin()) / TILE_SIZE).floor();
+        let max = ((position + half_size - self.origin()) / TILE_SIZE).floor();
+        (min.as_ivec2(), max.as_ivec2())
+    }
+
+    /// Whether a square of `half_size` around `position` overlaps any wall
+    pub fn is_blocked(&self, position: Vec2, half_size: f32) -> bool {
+        let (min, max) = self.tile_range(position, half_size);
+        for y in min.y..=max.y {
+            for x in min.x..=max.x {
+                if self.tile(x, y) == Tile::Wall && self.overlaps_tile(position, half_size, x, y) {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    fn overlaps_tile(&self, position: Vec2, half_size: f32, x: i32, y: i32) -> bool {
+        let tile_min = self.origin() + Vec2::new(x as f32, y as f32) * TILE_SIZE;
+        let tile_max = tile_min + TILE_SIZE;
+        position.x + half_size > tile_min.x
+            && position.x - half_size < tile_max.x
+            && position.y + half_size > tile_min.y
+            && position.y - half_size < tile_max.y
+    }
+
+    /// Moves a square of `half_size` by `delta`, stopping it against any wall in the way. Each axis is moved
+    /// on its own, so running into a wall at an angle slides along it.
+    ///
+    /// Client prediction runs this too, so it must give the exact same result for the same input.
+    pub fn move_and_collide(&self, translation: &mut Vec3, delta: Vec2, half_size: f32) {
+        let mut position = translation.truncate();
+        self.move_axis(&mut position, delta.x, half_size, 0);
+        self.move_axis(&mut position, delta.y, half_size, 1);
+        translation.x = position.x;
+        translation.y = position.y;
+    }
+
+    fn move_axis(&self, position: &mut Vec2, delta: f32, half_size: f32, axis: usize) {
+        if delta == 0.0 {
+            return;
+        }
+        position[axis] += delta;
+
+        let (min, max) = self.tile_range(*position, half_size);
+        for y in min.y..=max.y {
+            for x in min.x..=max.x {
+                if self.tile(x, y) != Tile::Wall || !self.overlaps_tile(*position, half_size, x, y) {
+                    continue;
+                }
+
+                // Push back out of the side of the wall we came from
+                let tile_min = self.origin()[axis] + [x, y][axis] as f32 * TILE_SIZE;
+                if delta > 0.0 {
+                    position[axis] = position[axis].min(tile_min - half_size);
+                } else {
+                    position[axis] = position[axis].max(tile_min + TILE_SIZE + half_size);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,100 @@
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime};
+
+use anyhow::bail;
+use bevy_renet::renet::{ConnectToken, NETCODE_KEY_BYTES, NETCODE_USER_DATA_BYTES};
+
+use crate::PROTOCOL_ID;
+
+/// Issued tokens can be used to connect for this long
+pub const TOKEN_EXPIRE_SECONDS: u64 = 60 * 60;
+/// Seconds without packets before a connection made with an issued token times out
+pub const TOKEN_TIMEOUT_SECONDS: i32 = 15;
+
+/// The longest username that fits in the user data, next to its length and the class
+pub const MAX_USERNAME_BYTES: usize = NETCODE_USER_DATA_BYTES - 9;
+
+/// Puts the username and the index of the chosen class in renet user data. The length of the username
+/// comes first, the class is the very last byte. Fails if the username is longer than `MAX_USERNAME_BYTES`.
+pub fn player_user_data(username: &str, class: usize) -> anyhow::Result<[u8; NETCODE_USER_DATA_BYTES]> {
+    let mut user_data = [0u8; NETCODE_USER_DATA_BYTES];
+    if username.len() > MAX_USERNAME_BYTES {
+        bail!("The username can be at most {} bytes long", MAX_USERNAME_BYTES);
+    }
+    user_data[0..8].copy_from_slice(&(username.len() as u64).to_le_bytes());
+    user_data[8..username.len() + 8].copy_from_slice(username.as_bytes());
+    user_data[NETCODE_USER_DATA_BYTES - 1] = class as u8;
+    Ok(user_data)
+}
+
+/// A connect token along with the parts of it the client needs but can't read, since they are encrypted with
+/// the private key
+pub struct IssuedToken {
+    pub client_id: u64,
+    pub username: String,
+    /// Index of the class the player will join as
+    pub class: usize,
+    pub connect_token: ConnectToken,
+}
+
+impl IssuedToken {
+    /// Issues a token for a new random client id, so players can't pick their own
+    pub fn generate(username: &str, class: usize, server_addr: SocketAddr, private_key: &[u8; NETCODE_KEY_BYTES]) -> anyhow::Result<Self> {
+        let user_data = player_user_data(username, class)?;
+        let client_id = rand::random::<u64>();
+        let current_time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+        let connect_token = ConnectToken::generate(
+            current_time,
+            PROTOCOL_ID,
+            TOKEN_EXPIRE_SECONDS,
+            client_id,
+            TOKEN_TIMEOUT_SECONDS,
+            vec![server_addr],
+            Some(&user_data),
+            private_key,
+        )?;
+
+        Ok(Self {
+            client_id,
+            username: username.to_string(),
+            class,
+            connect_token,
+        })
+    }
+
+    /// How long until the token can't be used anymore, zero if it already expired
+    pub fn expires_in(&self) -> Duration {
+        let current_time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+        Duration::from_secs(self.connect_token.expire_timestamp).saturating_sub(current_time)
+    }
+
+    pub fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        writer.write_all(&self.client_id.to_le_bytes())?;
+        writer.write_all(&(self.username.len() as u64).to_le_bytes())?;
+        writer.write_all(self.username.as_bytes())?;
+        writer.write_all(&(self.class as u64).to_le_bytes())?;
+        self.connect_token.write(writer)
+    }
+
+    pub fn read(reader: &mut impl Read) -> anyhow::Result<Self> {
+        let mut buffer = [0u8; 8];
+        reader.read_exact(&mut buffer)?;
+        let client_id = u64::from_le_bytes(buffer);
+
+        reader.read_exact(&mut buffer)?;
+        let username_len = (u64::from_le_bytes(buffer) as usize).min(MAX_USERNAME_BYTES);
+        let mut username = vec![0u8; username_len];
+        reader.read_exact(&mut username)?;
+
+        reader.read_exact(&mut buffer)?;
+        let class = u64::from_le_bytes(buffer) as usize;
+
+        Ok(Self {
+            client_id,
+            username: String::from_utf8(username)?,
+            class,
+            connect_token: ConnectToken::read(reader)?,
+        })
+    }
+}
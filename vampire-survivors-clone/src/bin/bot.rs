@@ -0,0 +1,479 @@
+use std::net::{SocketAddr, UdpSocket};
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use bevy::prelude::*;
+use clap::{CommandFactory, Parser};
+use clap::builder::FalseyValueParser;
+use clap::error::ErrorKind;
+use rand::Rng;
+use renet::{ClientAuthentication, RenetClient};
+use serde::Deserialize;
+
+use vampire_surviors_clone::{client_connection_config, ClientChannel, INPUT_TIMESTEP, MatchPhase, NetworkFrame, PlayerCommand, PlayerInput, PORT, PRIVATE_KEY, PROTOCOL_ID, ServerChannel, ServerMessages, TICK_RATE, VERSION};
+use vampire_surviors_clone::auth::{IssuedToken, player_user_data};
+use vampire_surviors_clone::classes::{CharacterClasses, CLASSES_PATH};
+use vampire_surviors_clone::config::{parse_host, read_config_file, validate_port};
+use vampire_surviors_clone::snapshot::{dequantize, FrameAssembler};
+use vampire_surviors_clone::weapons::{WeaponDefinitions, WEAPONS_PATH};
+
+/// Options for the bots, taken from the command line, then environment variables, then the config file
+#[derive(Debug, Default, Parser, Deserialize)]
+#[command(name = "bot", version = VERSION, about = "Connects a bunch of headless players to load test the server")]
+#[serde(default, deny_unknown_fields)]
+struct BotArgs {
+    /// JSON file with defaults for any of the other options, named like the long flags with underscores
+    #[arg(short, long, env = "BOT_CONFIG")]
+    #[serde(skip)]
+    config: Option<PathBuf>,
+    /// Amount of bots to connect, the server needs room for all of them [default: 8]
+    #[arg(short, long, env = "BOTS")]
+    bots: Option<usize>,
+    /// IP address of the server [default: 127.0.0.1]
+    #[arg(long, env = "SERVER_HOST")]
+    host: Option<String>,
+    /// Port of the server [default: 5000]
+    #[arg(long, env = "SERVER_PORT")]
+    port: Option<u16>,
+    /// Class every bot plays as [default: a random one for each bot]
+    #[arg(long, env = "BOT_CLASS")]
+    class: Option<String>,
+    /// JSON file with the steps every bot repeats, instead of walking around at random
+    #[arg(short, long, env = "BOT_SCRIPT")]
+    script: Option<PathBuf>,
+    /// Connect without tokens, for servers started with --unsecure. Otherwise every bot issues itself a token.
+    #[arg(long, env = "UNSECURE", value_parser = FalseyValueParser::new())]
+    unsecure: bool,
+    /// Seconds between two reports [default: 5]
+    #[arg(long, env = "REPORT_INTERVAL")]
+    report_interval: Option<f32>,
+    /// Seconds to run for before disconnecting every bot [default: until stopped]
+    #[arg(long, env = "BOT_DURATION")]
+    duration: Option<f32>,
+}
+
+/// One step of a script, held for `seconds` before moving on to the next one
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ScriptStep {
+    seconds: f32,
+    #[serde(default)]
+    up: bool,
+    #[serde(default)]
+    down: bool,
+    #[serde(default)]
+    left: bool,
+    #[serde(default)]
+    right: bool,
+    /// Fire at a random spot near the bot every `ATTACK_INTERVAL` during this step
+    #[serde(default)]
+    attack: bool,
+}
+
+/// The bot options once everything has been filled in and checked
+struct BotOptions {
+    bots: usize,
+    server_addr: SocketAddr,
+    /// `None` picks a random class for every bot
+    class: Option<usize>,
+    script: Option<Vec<ScriptStep>>,
+    secure: bool,
+    report_interval: Duration,
+    duration: Option<Duration>,
+}
+
+impl BotArgs {
+    /// Fills in whatever wasn't given from the config file and the defaults, exiting with an error
+    /// when any of it doesn't make sense
+    fn options(self, classes: &CharacterClasses) -> BotOptions {
+        self.resolve(classes).unwrap_or_else(|message| BotArgs::command().error(ErrorKind::ValueValidation, message).exit())
+    }
+
+    fn resolve(self, classes: &CharacterClasses) -> Result<BotOptions, String> {
+        let file: BotArgs = match &self.config {
+            Some(path) => read_config_file(path).map_err(|err| format!("{:#}", err))?,
+            None => BotArgs::default(),
+        };
+
+        let bots = self.bots.or(file.bots).unwrap_or(DEFAULT_BOTS);
+        if bots == 0 {
+            return Err("There has to be at least one bot".to_string());
+        }
+
+        let port = validate_port(self.port.or(file.port).unwrap_or(PORT))?;
+        let host = parse_host(self.host.or(file.host).as_deref().unwrap_or("127.0.0.1"))?;
+
+        let class = match self.class.or(file.class) {
+            Some(name) => Some(classes.0.iter().position(|class| class.name.eq_ignore_ascii_case(&name)).ok_or_else(|| {
+                let names: Vec<&str> = classes.0.iter().map(|class| class.name.as_str()).collect();
+                format!("Unknown class {}, pick one of {}", name, names.join(", "))
+            })?),
+            None => None,
+        };
+
+        let script = match self.script.or(file.script) {
+            Some(path) => {
+                let steps: Vec<ScriptStep> = read_config_file(&path).map_err(|err| format!("{:#}", err))?;
+                if steps.is_empty() {
+                    return Err(format!("The script in {} has no steps", path.display()));
+                }
+                if steps.iter().any(|step| !step.seconds.is_finite() || step.seconds <= 0.0) {
+                    return Err(format!("Every step in {} has to last longer than 0 seconds", path.display()));
+                }
+                Some(steps)
+            }
+            None => None,
+        };
+
+        let report_interval = self.report_interval.or(file.report_interval).unwrap_or(DEFAULT_REPORT_INTERVAL);
+        if !report_interval.is_finite() || report_interval <= 0.0 {
+            return Err("The report interval has to be above 0".to_string());
+        }
+
+        let duration = match self.duration.or(file.duration) {
+            Some(duration) if !duration.is_finite() || duration <= 0.0 => return Err("The duration has to be above 0".to_string()),
+            Some(duration) => Some(Duration::from_secs_f32(duration)),
+            None => None,
+        };
+
+        Ok(BotOptions {
+            bots,
+            server_addr: SocketAddr::new(host, port),
+            class,
+            script,
+            secure: !(self.unsecure || file.unsecure),
+            report_interval: Duration::from_secs_f32(report_interval),
+            duration,
+        })
+    }
+}
+
+const DEFAULT_BOTS: usize = 8;
+const DEFAULT_REPORT_INTERVAL: f32 = 5.0;
+/// Seconds between two attacks, well below the command rate limit of the server
+const ATTACK_INTERVAL: f32 = 0.5;
+/// Attacks are aimed at a random spot this far from the bot at most
+const ATTACK_RANGE: f32 = 300.0;
+/// Random bots walk in one direction for somewhere between these many seconds
+const WANDER_TIME: (f32, f32) = (0.5, 2.0);
+/// Input steps a bot catches up on at most when the process falls behind, more would get it kicked
+const MAX_CATCH_UP_STEPS: u32 = 3;
+
+/// What a bot does with its inputs
+enum Behaviour {
+    /// Walks in a random direction for a while, then picks another one
+    Wander { input: PlayerInput, time_left: f32 },
+    /// Repeats the steps of a script, starting at a random point so the bots don't all move in lockstep
+    Script { steps: Vec<ScriptStep>, step: usize, time_left: f32 },
+}
+
+impl Behaviour {
+    fn new(script: Option<&Vec<ScriptStep>>) -> Self {
+        let mut rng = rand::thread_rng();
+        match script {
+            Some(steps) => {
+                let step = rng.gen_range(0..steps.len());
+                Self::Script { steps: steps.clone(), step, time_left: steps[step].seconds }
+            }
+            None => Self::Wander { input: PlayerInput::default(), time_left: 0.0 },
+        }
+    }
+
+    /// Moves on by one input step, returning which way to walk and whether to attack
+    fn step(&mut self) -> (PlayerInput, bool) {
+        let mut rng = rand::thread_rng();
+        match self {
+            Self::Wander { input, time_left } => {
+                *time_left -= INPUT_TIMESTEP;
+                if *time_left <= 0.0 {
+                    *time_left = rng.gen_range(WANDER_TIME.0..WANDER_TIME.1);
+                    input.up = rng.gen_bool(0.5);
+                    input.down = !input.up && rng.gen_bool(0.5);
+                    input.left = rng.gen_bool(0.5);
+                    input.right = !input.left && rng.gen_bool(0.5);
+                }
+                (*input, true)
+            }
+            Self::Script { steps, step, time_left } => {
+                *time_left -= INPUT_TIMESTEP;
+                if *time_left <= 0.0 {
+                    *step = (*step + 1) % steps.len();
+                    *time_left = steps[*step].seconds;
+                }
+                let current = &steps[*step];
+                let input = PlayerInput {
+                    up: current.up,
+                    down: current.down,
+                    left: current.left,
+                    right: current.right,
+                    ..Default::default()
+                };
+                (input, current.attack)
+            }
+        }
+    }
+}
+
+/// What the server last told us in `ServerMessages::ServerStats`
+#[derive(Debug, Clone, Copy)]
+struct ServerStats {
+    frame_time: f32,
+    max_frame_time: f32,
+    ticks_per_second: f32,
+    players: u32,
+    enemies: u32,
+}
+
+/// A headless player, doing what a real client does over the network without drawing anything
+struct Bot {
+    username: String,
+    client: RenetClient,
+    behaviour: Behaviour,
+    input: PlayerInput,
+    attack_cooldown: f32,
+    /// Our player entity on the server, once it told us about it
+    server_entity: Option<Entity>,
+    translation: Vec3,
+    frames: FrameAssembler,
+    /// Why the bot isn't connected anymore
+    disconnected: Option<String>,
+    server_stats: Option<ServerStats>,
+}
+
+impl Bot {
+    fn new(index: usize, class: usize, server_addr: SocketAddr, secure: bool, behaviour: Behaviour) -> anyhow::Result<Self> {
+        let username = format!("Bot_{}", index + 1);
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        let current_time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+
+        let (client_id, authentication) = if secure {
+            // The bots know the private key anyway, so they issue their own tokens like the token binary would
+            let token = IssuedToken::generate(&username, class, server_addr, PRIVATE_KEY)?;
+            (token.client_id, ClientAuthentication::Secure { connect_token: token.connect_token })
+        } else {
+            // All bots start in the same millisecond, so the index keeps their ids apart
+            let client_id = current_time.as_millis() as u64 + index as u64;
+            let authentication = ClientAuthentication::Unsecure {
+                client_id,
+                protocol_id: PROTOCOL_ID,
+                server_addr,
+                user_data: Some(player_user_data(&username, class)?),
+            };
+            (client_id, authentication)
+        };
+
+        let client = RenetClient::new(current_time, socket, client_id, client_connection_config(), authentication)?;
+        Ok(Self {
+            username,
+            client,
+            behaviour,
+            input: PlayerInput::default(),
+            attack_cooldown: 0.0,
+            server_entity: None,
+            translation: Vec3::ZERO,
+            frames: FrameAssembler::default(),
+            disconnected: None,
+            server_stats: None,
+        })
+    }
+
+    fn update(&mut self, delta: Duration, input_steps: u32) {
+        if self.disconnected.is_some() {
+            return;
+        }
+
+        if let Err(err) = self.client.update(delta) {
+            println!("{} disconnected: {}", self.username, err);
+            self.disconnected = Some(err.to_string());
+            return;
+        }
+
+        if self.client.is_connected() {
+            self.receive_messages();
+            self.receive_frames();
+            for _ in 0..input_steps {
+                self.send_input();
+            }
+        }
+
+        if let Err(err) = self.client.send_packets() {
+            println!("{} could not send: {}", self.username, err);
+            self.disconnected = Some(err.to_string());
+        }
+    }
+
+    fn send_command(&mut self, command: PlayerCommand) {
+        let message = bincode::serialize(&command).unwrap();
+        self.client.send_message(ClientChannel::Command.id(), message);
+    }
+
+    fn receive_messages(&mut self) {
+        let mut rng = rand::thread_rng();
+        while let Some(message) = self.client.receive_message(ServerChannel::ServerMessages.id()) {
+            let server_message = match bincode::deserialize(&message) {
+                Ok(server_message) => server_message,
+                Err(err) => {
+                    println!("{} received a message it doesn't understand: {}", self.username, err);
+                    continue;
+                }
+            };
+
+            match server_message {
+                ServerMessages::PlayerCreate { entity, id, translation, .. } if id == self.client.client_id() => {
+                    self.server_entity = Some(entity);
+                    self.translation = Vec3::new(translation[0], translation[1], 0.0);
+                }
+                // Ready up right away, so the match starts as soon as every bot is in
+                ServerMessages::MatchPhase { phase: MatchPhase::Lobby | MatchPhase::GameOver { .. } } => {
+                    self.send_command(PlayerCommand::SetReady { ready: true });
+                }
+                ServerMessages::LevelUp { choices } => {
+                    let choice = rng.gen_range(0..choices.len().max(1));
+                    self.send_command(PlayerCommand::ChooseUpgrade { choice });
+                }
+                ServerMessages::Kicked { reason } => {
+                    println!("{} was kicked: {}", self.username, reason);
+                }
+                ServerMessages::ServerStats { frame_time, max_frame_time, ticks_per_second, players, enemies } => {
+                    self.server_stats = Some(ServerStats { frame_time, max_frame_time, ticks_per_second, players, enemies });
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Puts frames back together like the client does, so the server can keep sending them against a baseline
+    fn receive_frames(&mut self) {
+        while let Some(message) = self.client.receive_message(ServerChannel::NetworkFrame.id()) {
+            let frame = match NetworkFrame::from_bytes(&message) {
+                Ok(frame) => frame,
+                Err(_) => continue,
+            };
+            if let Some(frame) = self.frames.receive(frame) {
+                self.input.most_recent_tick = Some(frame.tick);
+                if let Some(translation) = self.server_entity.and_then(|entity| frame.snapshot.translations.get(&entity)) {
+                    self.translation = dequantize(*translation);
+                }
+            }
+        }
+    }
+
+    /// Sends one input, exactly like a client does every input step
+    fn send_input(&mut self) {
+        let (movement, attack) = self.behaviour.step();
+        self.input.up = movement.up;
+        self.input.down = movement.down;
+        self.input.left = movement.left;
+        self.input.right = movement.right;
+        self.input.sequence += 1;
+        let message = bincode::serialize(&self.input).unwrap();
+        self.client.send_message(ClientChannel::Input.id(), message);
+
+        self.attack_cooldown -= INPUT_TIMESTEP;
+        if attack && self.attack_cooldown <= 0.0 {
+            self.attack_cooldown = ATTACK_INTERVAL;
+            let mut rng = rand::thread_rng();
+            let offset = Vec2::new(rng.gen_range(-ATTACK_RANGE..ATTACK_RANGE), rng.gen_range(-ATTACK_RANGE..ATTACK_RANGE));
+            self.send_command(PlayerCommand::BasicAttack { cast_at: self.translation + offset.extend(0.0) });
+        }
+    }
+}
+
+/// Prints how the server and the network are holding up, averaged over the connected bots
+fn report(bots: &[Bot]) {
+    let connected: Vec<&Bot> = bots.iter().filter(|bot| bot.client.is_connected()).collect();
+    let disconnected = bots.iter().filter(|bot| bot.disconnected.is_some()).count();
+    println!("{} bots connected, {} connecting, {} disconnected", connected.len(), bots.len() - connected.len() - disconnected, disconnected);
+    if connected.is_empty() {
+        return;
+    }
+
+    if let Some(stats) = connected.iter().find_map(|bot| bot.server_stats) {
+        println!(
+            "  server: frame time {:.2} ms, max {:.2} ms, {:.1} ticks per second, {} players, {} enemies",
+            stats.frame_time, stats.max_frame_time, stats.ticks_per_second, stats.players, stats.enemies
+        );
+        // Falling a little short happens whenever a report lands between two ticks, so leave some room
+        if stats.ticks_per_second < TICK_RATE as f32 * 0.95 {
+            println!("  the server is falling behind, it should simulate {} ticks per second", TICK_RATE);
+        }
+    }
+
+    let count = connected.len() as f32;
+    let mut rtt = 0.0;
+    let mut packet_loss = 0.0;
+    let mut sent_kbps = 0.0;
+    let mut received_kbps = 0.0;
+    for bot in connected.iter() {
+        let network_info = bot.client.network_info();
+        rtt += network_info.rtt;
+        packet_loss += network_info.packet_loss;
+        sent_kbps += network_info.sent_bandwidth_kbps;
+        received_kbps += network_info.received_bandwidth_kbps;
+    }
+    println!(
+        "  per bot: rtt {:.0} ms, packet loss {:.1}%, sent {:.1} kbps, received {:.1} kbps",
+        rtt / count,
+        packet_loss / count * 100.0,
+        sent_kbps / count,
+        received_kbps / count
+    );
+    println!("  all bots: sent {:.1} kbps, received {:.1} kbps", sent_kbps, received_kbps);
+}
+
+fn main() {
+    let weapon_definitions = WeaponDefinitions::load(WEAPONS_PATH).expect("Could not load the weapon definitions");
+    let classes = CharacterClasses::load(CLASSES_PATH, &weapon_definitions).expect("Could not load the classes");
+    let BotOptions { bots: amount_of_bots, server_addr, class, script, secure, report_interval, duration } = BotArgs::parse().options(&classes);
+
+    println!("Connecting {} bots to {}", amount_of_bots, server_addr);
+    println!("Version: {}", VERSION);
+
+    let mut rng = rand::thread_rng();
+    let mut bots: Vec<Bot> = (0..amount_of_bots)
+        .map(|index| {
+            let class = class.unwrap_or_else(|| rng.gen_range(0..classes.0.len()));
+            Bot::new(index, class, server_addr, secure, Behaviour::new(script.as_ref())).expect("Could not create a bot")
+        })
+        .collect();
+
+    let started = Instant::now();
+    let mut last_update = Instant::now();
+    let mut last_report = Instant::now();
+    // Inputs go out at a fixed rate no matter how long an update takes, like the client's fixed timestep
+    let mut input_time = 0.0;
+    loop {
+        let now = Instant::now();
+        let delta = now - last_update;
+        last_update = now;
+
+        input_time += delta.as_secs_f32();
+        let input_steps = ((input_time / INPUT_TIMESTEP) as u32).min(MAX_CATCH_UP_STEPS);
+        input_time = (input_time - input_steps as f32 * INPUT_TIMESTEP).min(INPUT_TIMESTEP);
+
+        for bot in bots.iter_mut() {
+            bot.update(delta, input_steps);
+        }
+
+        if now - last_report >= report_interval {
+            last_report = now;
+            report(&bots);
+        }
+
+        if bots.iter().all(|bot| bot.disconnected.is_some()) {
+            println!("Every bot got disconnected");
+            std::process::exit(1);
+        }
+
+        if duration.is_some_and(|duration| now - started >= duration) {
+            report(&bots);
+            for bot in bots.iter_mut() {
+                bot.client.disconnect();
+            }
+            return;
+        }
+
+        thread::sleep(Duration::from_millis(1));
+    }
+}
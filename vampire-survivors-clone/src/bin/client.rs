@@ -1,48 +1,134 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
-use std::collections::HashMap;
-use std::net::UdpSocket;
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::marker::PhantomData;
+use std::net::{SocketAddr, UdpSocket};
+use std::path::PathBuf;
 use std::time::SystemTime;
 
 use bevy::app::AppExit;
+use bevy::ecs::system::SystemParam;
 use bevy::prelude::*;
+use bevy::sprite::{Anchor, MaterialMesh2dBundle};
+use bevy::time::FixedTimestep;
 use bevy::window::WindowSettings;
+use bevy_egui::{egui, EguiContext, EguiPlugin};
 use bevy_renet::{RenetClientPlugin, run_if_client_connected};
-use renet::{ClientAuthentication, NETCODE_USER_DATA_BYTES, RenetClient, RenetError};
+use clap::{CommandFactory, Parser};
+use clap::error::ErrorKind;
+use renet::{ClientAuthentication, RenetClient, RenetError};
+use renet_visualizer::{RenetClientVisualizer, RenetVisualizerStyle};
+use serde::Deserialize;
 
-use vampire_surviors_clone::{client_connection_config, ClientChannel, NetworkFrame, PlayerCommand, PlayerInput, PORT, PROTOCOL_ID, ServerChannel, ServerMessages, translate_host, translate_port, VERSION};
+use vampire_surviors_clone::{client_connection_config, ClientChannel, Difficulty, EnemyKind, INPUT_TIMESTEP, MatchPhase, move_player, NetworkFrame, Pickup, PlayerCommand, PlayerInput, PORT, PROTOCOL_ID, ServerChannel, ServerMessages, TICK_RATE, VERSION};
+use vampire_surviors_clone::arena::{Arena, ARENA_PATH, Tile, TILE_SIZE};
+use vampire_surviors_clone::auth::{IssuedToken, MAX_USERNAME_BYTES, player_user_data};
+use vampire_surviors_clone::classes::{CharacterClass, CharacterClasses, CLASSES_PATH};
+use vampire_surviors_clone::config::{parse_host, read_config_file, validate_port};
+use vampire_surviors_clone::snapshot::{CompleteFrame, dequantize, FrameAssembler};
+use vampire_surviors_clone::weapons::{UpgradeChoice, WeaponDefinitions, WEAPONS_PATH};
 
-fn main() {
-    let args = std::env::args().collect::<Vec<String>>();
-
-    let mut username = format!("Player_{}", SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis());
-    let mut host = "127.0.0.1";
-    let mut port = PORT;
-    match args.len() {
-        2 => {
-            username = args[1].clone();
-            println!("Username set to: {}", username);
-        }
-        3 => {
-            username = args[1].clone();
-            host = translate_host(&args[2], "");
-            println!("Host has been set to: {}, Username has been set to: {}", host, username);
+/// Options for the client, taken from the command line, then environment variables, then the config file
+#[derive(Debug, Default, Parser, Deserialize)]
+#[command(name = "client", version = VERSION, about = "Joins a match of the vampire survivors clone")]
+#[serde(default, deny_unknown_fields)]
+struct ClientArgs {
+    /// JSON file with defaults for any of the other options, named like the long flags
+    #[arg(short, long, env = "CLIENT_CONFIG")]
+    #[serde(skip)]
+    config: Option<PathBuf>,
+    /// Name shown to the other players [default: Player_<timestamp>]
+    #[arg(short, long, env = "PLAYER_NAME")]
+    username: Option<String>,
+    /// IP address of the server [default: 127.0.0.1]
+    #[arg(long, env = "SERVER_HOST")]
+    host: Option<String>,
+    /// Port of the server [default: 5000]
+    #[arg(long, env = "SERVER_PORT")]
+    port: Option<u16>,
+    /// Connect token from the token binary, needed for secure servers. The username, class and server come
+    /// from the token, so the other options are ignored.
+    #[arg(short, long, env = "TOKEN_FILE")]
+    token: Option<PathBuf>,
+}
+
+/// How the client connects, once everything has been filled in and checked
+enum Connection {
+    Unsecure { username: String, server_addr: SocketAddr },
+    Secure(IssuedToken),
+}
+
+impl ClientArgs {
+    /// Fills in whatever wasn't given from the config file and the defaults, exiting with an error
+    /// when any of it doesn't make sense
+    fn connection(self) -> Connection {
+        self.resolve().unwrap_or_else(|message| ClientArgs::command().error(ErrorKind::ValueValidation, message).exit())
+    }
+
+    fn resolve(self) -> Result<Connection, String> {
+        let file: ClientArgs = match &self.config {
+            Some(path) => read_config_file(path).map_err(|err| format!("{:#}", err))?,
+            None => ClientArgs::default(),
+        };
+
+        // With a token from the token binary we connect securely, and the username and server come from the token
+        if let Some(path) = self.token.or(file.token) {
+            let mut token_file = File::open(&path).map_err(|err| format!("Could not open {}: {}", path.display(), err))?;
+            let token = IssuedToken::read(&mut token_file).map_err(|err| format!("Could not read {}: {}", path.display(), err))?;
+            if token.expires_in().is_zero() {
+                return Err(format!("The token in {} has expired, issue a new one", path.display()));
+            }
+            return Ok(Connection::Secure(token));
         }
-        4 => {
-            username = args[1].clone();
-            host = translate_host(&args[2], "");
-            port = translate_port(&args[3]);
-            println!("Port has been set to: {}, Host has been set to: {}, Username has been set to: {}", port, host, username);
+
+        let username = self.username.or(file.username).unwrap_or_else(|| {
+            format!("Player_{}", SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis())
+        });
+        if username.trim().is_empty() {
+            return Err("The username can't be empty".to_string());
         }
-        _ => {
-            println!("Usage: client [username] [host] [port]");
-            println!("Default values: username: {}, host: {}, port: {}", username, host, port);
+        if username.len() > MAX_USERNAME_BYTES {
+            return Err(format!("The username can be at most {} bytes long", MAX_USERNAME_BYTES));
         }
+
+        let port = validate_port(self.port.or(file.port).unwrap_or(PORT))?;
+        let host = parse_host(self.host.or(file.host).as_deref().unwrap_or("127.0.0.1"))?;
+
+        Ok(Connection::Unsecure { username, server_addr: SocketAddr::new(host, port) })
     }
+}
+
+fn main() {
+    let connection = ClientArgs::parse().connection();
+    let username = match &connection {
+        Connection::Unsecure { username, server_addr } => {
+            println!("Connecting to {} as {}", server_addr, username);
+            username.clone()
+        }
+        Connection::Secure(token) => {
+            println!("Connecting with the token for {}", token.username);
+            token.username.clone()
+        }
+    };
 
     println!("Version: {}", VERSION);
 
-    App::new()
+    let weapon_definitions = WeaponDefinitions::load(WEAPONS_PATH).expect("Could not load the weapon definitions");
+    let classes = CharacterClasses::load(CLASSES_PATH, &weapon_definitions).expect("Could not load the classes");
+
+    let mut app = App::new();
+    // The class goes in the user data, so without a token we can only connect once the player has picked one
+    match connection {
+        Connection::Secure(token) => {
+            app.insert_resource(new_secure_renet_client(token));
+        }
+        Connection::Unsecure { username, server_addr } => {
+            app.insert_resource(ClassSelection { username, server_addr });
+        }
+    }
+
+    app
         .insert_resource(WindowDescriptor {
             title: format!("Vampire Survivors Clone <{}>", username),
             width: 480.0,
@@ -53,24 +139,44 @@ fn main() {
             ..default()
         })
         .insert_resource(ClearColor(Color::hex("282828").unwrap()))
-        .insert_resource(new_renet_client(&username, host, port))
         .insert_resource(ClientLobby::default())
         .insert_resource(PlayerInput::default())
+        .insert_resource(PendingInputs::default())
         .insert_resource(MostRecentTick(None))
         .insert_resource(NetworkMapping::default())
+        .insert_resource(ReceivedFrames::default())
+        .insert_resource(ChannelBandwidth::default())
+        .insert_resource(RenetClientVisualizer::<200>::new(RenetVisualizerStyle::default()))
+        .insert_resource(PlayerProgress::default())
+        .insert_resource(UpgradeChoices::default())
+        .insert_resource(ChestUpgrade::default())
+        .insert_resource(Arena::load(ARENA_PATH).expect("Could not load the arena"))
+        .insert_resource(weapon_definitions)
+        .insert_resource(classes)
 
         .add_plugins(DefaultPlugins)
         .add_plugin(RenetClientPlugin)
+        .add_plugin(EguiPlugin)
 
         .add_system(player_input)
-        .add_system(camera_follow)
-        .add_system(client_send_input.with_run_criteria(run_if_client_connected))
+        .add_system(player_attack)
+        .add_system(camera_follow.with_run_criteria(run_if_client_connected))
+        .add_system(pulse_effects)
+        .add_system(interpolate_remote_entities)
+        .add_system(progress_ui)
+        .add_system(class_selection_ui)
+        .add_system(match_ui.with_run_criteria(run_if_client_connected))
+        .add_system(scoreboard_ui)
+        .add_system(network_overlay.with_run_criteria(run_if_client_connected))
+        // Inputs are sent at a fixed rate, since every input moves the player for the same amount of time
+        .add_system(client_predict_movement.with_run_criteria(FixedTimestep::step(INPUT_TIMESTEP as f64)))
         .add_system(client_send_player_commands.with_run_criteria(run_if_client_connected))
         .add_system(client_sync_players.with_run_criteria(run_if_client_connected))
 
         .add_event::<PlayerCommand>()
 
         .add_startup_system(setup_camera)
+        .add_startup_system(setup_arena)
         .add_system(panic_on_error_system)
 
         .add_system_to_stage(CoreStage::Last, disconnect)
@@ -78,6 +184,8 @@ fn main() {
         .run();
 }
 
+const HEALTH_BAR_WIDTH: f32 = 32.0;
+
 #[derive(Component)]
 struct ControlledPlayer;
 
@@ -86,42 +194,221 @@ struct NetworkMapping(HashMap<Entity, Entity>);
 
 #[derive(Debug)]
 struct PlayerInfo {
+    username: String,
+    /// Index into the `CharacterClasses`
+    class: usize,
     client_entity: Entity,
     server_entity: Entity,
+    health_bar: Entity,
+    dead: bool,
 }
 
 #[derive(Debug, Default)]
 struct ClientLobby {
     players: HashMap<u64, PlayerInfo>,
+    host: Option<u64>,
+    difficulty: Difficulty,
+    ready: Vec<u64>,
+    /// `None` until the server tells us
+    phase: Option<MatchPhase>,
+    /// When we got the current phase, to count down from
+    phase_received_at: f64,
+}
+
+impl ClientLobby {
+    fn username(&self, id: u64) -> String {
+        self.players
+            .get(&id)
+            .map_or_else(|| format!("Player {}", id), |player_info| player_info.username.clone())
+    }
 }
 
 #[derive(Debug)]
 struct MostRecentTick(Option<u32>);
 
+#[derive(Debug, Default)]
+struct ReceivedFrames {
+    assembler: FrameAssembler,
+    /// When the last complete frame came in
+    last_frame_at: f64,
+    server_clock: ServerClock,
+}
+
+/// How quickly the estimated server clock follows the frames coming in. Lower smooths out more jitter.
+const CLOCK_SMOOTHING: f64 = 0.05;
+/// Seconds the estimate can be off by before it's reset instead of smoothed, like on the first frame or after a stall
+const CLOCK_RESET_THRESHOLD: f64 = 0.5;
+
+/// Our estimate of the server's clock, going by the ticks of the frames it sends us
+#[derive(Debug, Default)]
+struct ServerClock {
+    /// Seconds to add to our own time to get the server's, once the first frame came in
+    offset: Option<f64>,
+}
+
+impl ServerClock {
+    fn update(&mut self, tick: u32, now: f64) {
+        let offset = tick_time(tick) - now;
+        self.offset = Some(match self.offset {
+            Some(current) if (offset - current).abs() < CLOCK_RESET_THRESHOLD => current + (offset - current) * CLOCK_SMOOTHING,
+            _ => offset,
+        });
+    }
+
+    /// The server's time in seconds, as of the last frame that came in
+    fn now(&self, now: f64) -> Option<f64> {
+        self.offset.map(|offset| now + offset)
+    }
+}
+
+/// Seconds since the server started simulating, at the start of `tick`
+fn tick_time(tick: u32) -> f64 {
+    tick as f64 / TICK_RATE as f64
+}
+
+/// Bytes sent and received on each channel, counted a second at a time for the network overlay
+#[derive(Debug, Default)]
+struct ChannelBandwidth {
+    bytes: HashMap<&'static str, usize>,
+    /// What came out of the last full second
+    kbps: Vec<(&'static str, f32)>,
+    counting_since: f64,
+}
+
+impl ChannelBandwidth {
+    fn count(&mut self, channel: &'static str, bytes: usize) {
+        *self.bytes.entry(channel).or_insert(0) += bytes;
+    }
+}
+
+/// Who to connect as and where to, once the player has picked a class
+struct ClassSelection {
+    username: String,
+    server_addr: SocketAddr,
+}
+
+/// Everything we need to spawn the sprites of networked entities
+#[derive(SystemParam)]
+struct SpawnAssets<'w, 's> {
+    asset_server: Res<'w, AssetServer>,
+    classes: Res<'w, CharacterClasses>,
+    texture_atlases: ResMut<'w, Assets<TextureAtlas>>,
+    meshes: ResMut<'w, Assets<Mesh>>,
+    materials: ResMut<'w, Assets<ColorMaterial>>,
+    #[system_param(ignore)]
+    marker: PhantomData<&'s ()>,
+}
+
+/// Inputs we have sent and applied ourselves, but the server hasn't applied yet
+#[derive(Debug, Default)]
+struct PendingInputs(VecDeque<PlayerInput>);
+
+#[derive(Debug, Default)]
+struct PlayerProgress {
+    xp: u32,
+    xp_needed: u32,
+    level: u32,
+}
+
+/// The upgrades we can pick from right now, empty unless we just leveled up
+#[derive(Debug, Default)]
+struct UpgradeChoices(Vec<UpgradeChoice>);
+
+/// The last upgrade we got from a chest, with when we got it
+#[derive(Debug, Default)]
+struct ChestUpgrade(Option<(UpgradeChoice, f64)>);
+
+/// How many seconds the upgrade from a chest is shown
+const CHEST_UPGRADE_TIME: f64 = 3.0;
+
+/// A pulse ring growing to its full size before disappearing
+#[derive(Component)]
+struct PulseEffect(Timer);
+
+const PULSE_EFFECT_TIME: f32 = 0.3;
+
+/// Remote entities are shown this many seconds in the past, so there's usually a newer snapshot to move towards
+const INTERPOLATION_DELAY: f64 = 0.1;
+/// How long we keep moving an entity in the same direction when snapshots stop coming in
+const MAX_EXTRAPOLATION: f64 = 0.2;
+/// Snapshots this many seconds older than the newest one are dropped
+const SNAPSHOT_BUFFER_TIME: f64 = 1.0;
+
+#[derive(Debug, Clone, Copy)]
+struct Snapshot {
+    tick: u32,
+    translation: Vec3,
+}
+
+/// The last positions the server sent for a remote entity, oldest first
+#[derive(Debug, Default, Component)]
+struct SnapshotBuffer(VecDeque<Snapshot>);
+
+impl SnapshotBuffer {
+    fn push(&mut self, snapshot: Snapshot) {
+        // Frames can arrive twice, the first one wins
+        if self.0.back().is_some_and(|last| last.tick >= snapshot.tick) {
+            return;
+        }
+        self.0.push_back(snapshot);
+
+        while self.0.front().is_some_and(|first| tick_time(snapshot.tick) - tick_time(first.tick) > SNAPSHOT_BUFFER_TIME) {
+            self.0.pop_front();
+        }
+    }
+
+    /// Where the entity was at `render_time` on the server's clock, or is probably at if we haven't heard
+    /// from the server in a while. Going by the server's ticks keeps jitter in when frames arrive out of it.
+    fn sample(&self, render_time: f64) -> Option<Vec3> {
+        let last = self.0.back()?;
+        if render_time >= tick_time(last.tick) {
+            let previous = match self.0.len() {
+                0 | 1 => return Some(last.translation),
+                len => self.0[len - 2],
+            };
+            let elapsed = (render_time - tick_time(last.tick)).min(MAX_EXTRAPOLATION);
+            let velocity = (last.translation - previous.translation) / (tick_time(last.tick) - tick_time(previous.tick)) as f32;
+            return Some(last.translation + velocity * elapsed as f32);
+        }
+
+        let next_index = self.0.iter().position(|snapshot| tick_time(snapshot.tick) > render_time)?;
+        if next_index == 0 {
+            return Some(self.0[0].translation);
+        }
+
+        let (from, to) = (self.0[next_index - 1], self.0[next_index]);
+        let t = (render_time - tick_time(from.tick)) / (tick_time(to.tick) - tick_time(from.tick));
+        Some(from.translation.lerp(to.translation, t as f32))
+    }
+}
+
 ////////// RENET NETWORKING //////////
-fn new_renet_client(username: &String, host: &str, port: i32) -> RenetClient {
-    let server_addr = format!("{}:{}", host, port).parse().unwrap();
-    let socket = UdpSocket::bind(format!("0.0.0.0:0")).unwrap();
+fn new_renet_client(username: &String, class: usize, server_addr: SocketAddr) -> anyhow::Result<RenetClient> {
+    let socket = UdpSocket::bind(format!("0.0.0.0:0"))?;
     let connection_config = client_connection_config();
     let current_time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
     let client_id = current_time.as_millis() as u64;
 
-    // Place username in user data
-    let mut user_data = [0u8; NETCODE_USER_DATA_BYTES];
-    if username.len() > NETCODE_USER_DATA_BYTES - 8 {
-        panic!("Username is too big");
-    }
-    user_data[0..8].copy_from_slice(&(username.len() as u64).to_le_bytes());
-    user_data[8..username.len() + 8].copy_from_slice(username.as_bytes());
-
     let authentication = ClientAuthentication::Unsecure {
         client_id,
         protocol_id: PROTOCOL_ID,
         server_addr,
-        user_data: Some(user_data),
+        user_data: Some(player_user_data(username, class)?),
+    };
+
+    Ok(RenetClient::new(current_time, socket, client_id, connection_config, authentication)?)
+}
+
+/// Connects with a token issued by the token binary, which is the only way into a secure server
+fn new_secure_renet_client(token: IssuedToken) -> RenetClient {
+    let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
+    let connection_config = client_connection_config();
+    let current_time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+    let authentication = ClientAuthentication::Secure {
+        connect_token: token.connect_token,
     };
 
-    RenetClient::new(current_time, socket, client_id, connection_config, authentication).unwrap()
+    RenetClient::new(current_time, socket, token.client_id, connection_config, authentication).unwrap()
 }
 
 fn player_input(
@@ -136,104 +423,740 @@ fn player_input(
     player_input.most_recent_tick = most_recent_tick.0;
 }
 
-fn client_send_input(player_input: Res<PlayerInput>, mut client: ResMut<RenetClient>) {
-    let input_message = bincode::serialize(&*player_input).unwrap();
+fn player_attack(
+    mouse_input: Res<Input<MouseButton>>,
+    windows: Res<Windows>,
+    mut egui_context: ResMut<EguiContext>,
+    camera_query: Query<&Transform, With<Camera>>,
+    mut player_commands: EventWriter<PlayerCommand>,
+) {
+    if !mouse_input.just_pressed(MouseButton::Left) {
+        return;
+    }
+
+    // Clicking an upgrade shouldn't also fire at it
+    if egui_context.ctx_mut().wants_pointer_input() {
+        return;
+    }
+
+    let window = windows.get_primary().unwrap();
+    if let Some(cursor_position) = window.cursor_position() {
+        // The camera is a plain 2d camera, so the world position is just the cursor offset from the center of the screen
+        let camera_transform = camera_query.single();
+        let window_size = Vec2::new(window.width(), window.height());
+        let cast_at = camera_transform.translation.truncate() + cursor_position - window_size / 2.0;
+
+        player_commands.send(PlayerCommand::BasicAttack { cast_at: cast_at.extend(0.0) });
+    }
+}
+
+/// Sends our input to the server and moves our player right away, instead of waiting for the server to do it
+#[allow(clippy::too_many_arguments)]
+fn client_predict_movement(
+    mut player_input: ResMut<PlayerInput>,
+    mut pending_inputs: ResMut<PendingInputs>,
+    mut client: Option<ResMut<RenetClient>>,
+    mut bandwidth: ResMut<ChannelBandwidth>,
+    lobby: Res<ClientLobby>,
+    arena: Res<Arena>,
+    classes: Res<CharacterClasses>,
+    mut player_query: Query<&mut Transform, With<ControlledPlayer>>,
+) {
+    // There's no client until a class has been picked
+    let client = match client.as_mut() {
+        Some(client) if client.is_connected() => client,
+        _ => return,
+    };
 
+    player_input.sequence += 1;
+    let input_message = bincode::serialize(&*player_input).unwrap();
+    bandwidth.count("Input (sent)", input_message.len());
     client.send_message(ClientChannel::Input.id(), input_message);
+    pending_inputs.0.push_back(*player_input);
+
+    let speed = lobby
+        .players
+        .get(&client.client_id())
+        .filter(|player_info| !player_info.dead)
+        .map(|player_info| classes.get(player_info.class).speed);
+    if let (Some(speed), Ok(mut transform)) = (speed, player_query.get_single_mut()) {
+        move_player(&mut transform.translation, &player_input, speed, &arena);
+    }
 }
 
-fn client_send_player_commands(mut player_commands: EventReader<PlayerCommand>, mut client: ResMut<RenetClient>) {
+fn client_send_player_commands(
+    mut player_commands: EventReader<PlayerCommand>,
+    mut client: ResMut<RenetClient>,
+    mut bandwidth: ResMut<ChannelBandwidth>,
+) {
     for command in player_commands.iter() {
         let command_message = bincode::serialize(command).unwrap();
+        bandwidth.count("Commands (sent)", command_message.len());
         client.send_message(ClientChannel::Command.id(), command_message);
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn client_sync_players(
     mut commands: Commands,
     mut client: ResMut<RenetClient>,
     mut lobby: ResMut<ClientLobby>,
     mut network_mapping: ResMut<NetworkMapping>,
     mut most_recent_tick: ResMut<MostRecentTick>,
-    asset_server: Res<AssetServer>,
-    mut texture_atlases: ResMut<Assets<TextureAtlas>>,
+    mut progress: ResMut<PlayerProgress>,
+    mut upgrade_choices: ResMut<UpgradeChoices>,
+    mut chest_upgrade: ResMut<ChestUpgrade>,
+    mut assets: SpawnAssets,
+    mut bandwidth: ResMut<ChannelBandwidth>,
+    mut pending_inputs: ResMut<PendingInputs>,
+    mut controlled_player_query: Query<&mut Transform, With<ControlledPlayer>>,
+    mut snapshot_buffers: Query<&mut SnapshotBuffer>,
+    mut received_frames: ResMut<ReceivedFrames>,
+    arena: Res<Arena>,
+    time: Res<Time>,
 ) {
     let client_id = client.client_id();
 
-    let texture_handle_self = asset_server.load("sprites/bob.png");
-    let texture_handle_others = asset_server.load("sprites/fritz.png");
-    let texture_atlas_self = TextureAtlas::from_grid(texture_handle_self, Vec2::new(32.0, 32.0), 1, 1);
-    let texture_atlas_others = TextureAtlas::from_grid(texture_handle_others, Vec2::new(32.0, 32.0), 1, 1);
-    let texture_atlas_handle_self = texture_atlases.add(texture_atlas_self);
-    let texture_atlas_handle_others = texture_atlases.add(texture_atlas_others);
+    let texture_handle_projectile = assets.asset_server.load("sprites/bullet.png");
+    let font = assets.asset_server.load("fonts/Inconsolata.ttf");
 
     while let Some(message) = client.receive_message(ServerChannel::ServerMessages.id()) {
+        bandwidth.count("Server messages", message.len());
         let server_message = bincode::deserialize(&message).unwrap();
         match server_message {
-            ServerMessages::PlayerCreate { id, translation, entity } => {
-                println!("Player {} connected.", id);
+            ServerMessages::PlayerCreate { id, username, class, translation, entity } => {
+                let class_definition = assets.classes.get(class);
+                println!("Player {} connected as {}.", username, class_definition.name);
 
                 let is_player = client_id == id;
-                let texture_atlas_handle = if is_player {
-                    texture_atlas_handle_self.clone()
-                } else {
-                    texture_atlas_handle_others.clone()
-                };
+                let texture_handle = assets.asset_server.load(&class_definition.sprite);
+                let texture_atlas = TextureAtlas::from_grid(texture_handle, Vec2::new(32.0, 32.0), 1, 1);
+                let texture_atlas_handle = assets.texture_atlases.add(texture_atlas);
+                let tint = class_tint(class_definition);
+
+                // Health bar floating above the sprite, the colored part shrinks towards the left as health goes down
+                let health_bar_background = commands
+                    .spawn_bundle(SpriteBundle {
+                        sprite: Sprite {
+                            color: Color::hex("3c3836").unwrap(),
+                            custom_size: Some(Vec2::new(HEALTH_BAR_WIDTH, 4.0)),
+                            ..Default::default()
+                        },
+                        transform: Transform::from_xyz(0.0, 22.0, 0.1),
+                        ..Default::default()
+                    })
+                    .id();
+                let health_bar = commands
+                    .spawn_bundle(SpriteBundle {
+                        sprite: health_bar_sprite(1.0),
+                        transform: Transform::from_xyz(-HEALTH_BAR_WIDTH / 2.0, 22.0, 0.2),
+                        ..Default::default()
+                    })
+                    .id();
+                let name_label = commands
+                    .spawn_bundle(Text2dBundle {
+                        text: Text::from_section(
+                            username.clone(),
+                            TextStyle {
+                                font: font.clone(),
+                                font_size: 14.0,
+                                color: Color::hex("ebdbb2").unwrap(),
+                            },
+                        )
+                            .with_alignment(TextAlignment::CENTER),
+                        transform: Transform::from_xyz(0.0, 34.0, 0.2),
+                        ..Default::default()
+                    })
+                    .id();
 
                 let mut client_entity = commands
                     .spawn_bundle(SpriteSheetBundle {
-                        texture_atlas: texture_atlas_handle.clone(),
-                        sprite: TextureAtlasSprite::new(0),
+                        texture_atlas: texture_atlas_handle,
+                        sprite: TextureAtlasSprite {
+                            color: tint,
+                            ..TextureAtlasSprite::new(0)
+                        },
                         transform: Transform::from_xyz(translation[0], translation[1], 0.0),
                         ..Default::default()
                     });
+                client_entity.push_children(&[health_bar_background, health_bar, name_label]);
 
                 if is_player {
                     client_entity.insert(ControlledPlayer);
                 }
 
                 let player_info = PlayerInfo {
+                    username,
+                    class,
                     server_entity: entity,
                     client_entity: client_entity.id(),
+                    health_bar,
+                    dead: false,
                 };
                 lobby.players.insert(id, player_info);
                 network_mapping.0.insert(entity, client_entity.id());
             }
             ServerMessages::PlayerRemove { id } => {
-                println!("Player {} disconnected.", id);
+                println!("Player {} disconnected.", lobby.username(id));
                 if let Some(PlayerInfo {
                                 server_entity,
                                 client_entity,
+                                ..
                             }) = lobby.players.remove(&id)
                 {
-                    commands.entity(client_entity).despawn();
+                    commands.entity(client_entity).despawn_recursive();
                     network_mapping.0.remove(&server_entity);
                 }
             }
+            ServerMessages::PlayerHealth { id, current, max } => {
+                if let Some(player_info) = lobby.players.get(&id) {
+                    commands.entity(player_info.health_bar).insert(health_bar_sprite(current / max));
+                }
+            }
+            ServerMessages::PlayerDied { id } => {
+                println!("Player {} died.", lobby.username(id));
+                if let Some(player_info) = lobby.players.get_mut(&id) {
+                    player_info.dead = true;
+                    // Dead players are ghosts until they respawn
+                    let mut color = class_tint(assets.classes.get(player_info.class));
+                    color.set_a(0.3);
+                    commands.entity(player_info.client_entity).insert(TextureAtlasSprite {
+                        color,
+                        ..TextureAtlasSprite::new(0)
+                    });
+                }
+            }
+            ServerMessages::PlayerRespawned { id, translation } => {
+                if let Some(player_info) = lobby.players.get_mut(&id) {
+                    player_info.dead = false;
+                    commands
+                        .entity(player_info.client_entity)
+                        .insert(TextureAtlasSprite {
+                            color: class_tint(assets.classes.get(player_info.class)),
+                            ..TextureAtlasSprite::new(0)
+                        })
+                        .insert(Transform::from_xyz(translation[0], translation[1], 0.0));
+
+                    // Don't interpolate from where they died to where they respawned
+                    if id != client_id {
+                        commands.entity(player_info.client_entity).insert(SnapshotBuffer::default());
+                    }
+                }
+            }
+            ServerMessages::SpawnProjectile { entity, translation } => {
+                let projectile_entity = commands.spawn_bundle(SpriteBundle {
+                    texture: texture_handle_projectile.clone(),
+                    transform: Transform::from_xyz(translation[0], translation[1], 0.0),
+                    ..Default::default()
+                });
+                network_mapping.0.insert(entity, projectile_entity.id());
+            }
+            ServerMessages::DespawnProjectile { entity } => {
+                if let Some(entity) = network_mapping.0.remove(&entity) {
+                    commands.entity(entity).despawn();
+                }
+            }
+            ServerMessages::SpawnEnemy { entity, kind, translation } => {
+                let enemy_entity = commands.spawn_bundle(SpriteBundle {
+                    sprite: enemy_sprite(kind),
+                    transform: Transform::from_xyz(translation[0], translation[1], 0.0),
+                    ..Default::default()
+                });
+                network_mapping.0.insert(entity, enemy_entity.id());
+            }
+            ServerMessages::DespawnEnemy { entity } => {
+                if let Some(entity) = network_mapping.0.remove(&entity) {
+                    commands.entity(entity).despawn();
+                }
+            }
+            ServerMessages::SpawnBlade { entity, translation } => {
+                let blade_entity = commands.spawn_bundle(SpriteBundle {
+                    sprite: Sprite {
+                        color: Color::hex("83a598").unwrap(),
+                        custom_size: Some(Vec2::splat(16.0)),
+                        ..Default::default()
+                    },
+                    texture: texture_handle_projectile.clone(),
+                    transform: Transform::from_xyz(translation[0], translation[1], 0.0),
+                    ..Default::default()
+                });
+                network_mapping.0.insert(entity, blade_entity.id());
+            }
+            ServerMessages::DespawnBlade { entity } => {
+                if let Some(entity) = network_mapping.0.remove(&entity) {
+                    commands.entity(entity).despawn();
+                }
+            }
+            ServerMessages::Pulse { translation, radius } => {
+                commands
+                    .spawn_bundle(MaterialMesh2dBundle {
+                        mesh: assets.meshes.add(shape::Circle::new(radius).into()).into(),
+                        material: assets.materials.add(ColorMaterial::from(Color::rgba(0.98, 0.74, 0.18, 0.3))),
+                        // Below everything else, it's only there to show what got hit
+                        transform: Transform::from_xyz(translation[0], translation[1], -0.5).with_scale(Vec3::ZERO),
+                        ..Default::default()
+                    })
+                    .insert(PulseEffect(Timer::from_seconds(PULSE_EFFECT_TIME, false)));
+            }
+            ServerMessages::SpawnPickup { entity, pickup, translation } => {
+                let pickup_entity = commands.spawn_bundle(SpriteBundle {
+                    sprite: pickup_sprite(pickup),
+                    // Pickups lie on the ground, below the players and enemies
+                    transform: Transform::from_xyz(translation[0], translation[1], -0.1),
+                    ..Default::default()
+                });
+                network_mapping.0.insert(entity, pickup_entity.id());
+            }
+            ServerMessages::DespawnPickup { entity } => {
+                if let Some(entity) = network_mapping.0.remove(&entity) {
+                    commands.entity(entity).despawn();
+                }
+            }
+            ServerMessages::PlayerExperience { xp, xp_needed, level } => {
+                *progress = PlayerProgress { xp, xp_needed, level };
+            }
+            ServerMessages::LevelUp { choices } => {
+                upgrade_choices.0 = choices;
+            }
+            ServerMessages::UpgradeGranted { upgrade } => {
+                // The server makes a new offer when the chest upgraded a weapon that was on offer
+                if upgrade_choices.0.iter().any(|offered| offered.weapon == upgrade.weapon) {
+                    upgrade_choices.0.clear();
+                }
+                chest_upgrade.0 = Some((upgrade, time.seconds_since_startup()));
+            }
+            ServerMessages::Lobby { host, difficulty, ready } => {
+                lobby.host = host;
+                lobby.difficulty = difficulty;
+                lobby.ready = ready;
+            }
+            ServerMessages::MatchPhase { phase } => {
+                // Whatever we were choosing belonged to the last match
+                if let MatchPhase::Countdown { .. } = phase {
+                    upgrade_choices.0.clear();
+                }
+                lobby.phase = Some(phase);
+                lobby.phase_received_at = time.seconds_since_startup();
+            }
+            ServerMessages::Kicked { reason } => {
+                println!("Kicked by the server: {}", reason);
+                std::process::exit(1);
+            }
+            // Meant for the bot binary, players see how the server holds up in the network overlay already
+            ServerMessages::ServerStats { .. } => {}
         }
     }
 
+    // Borrow the fields on their own, the complete frame keeps borrowing the assembler while we use the rest
+    let received_frames = &mut *received_frames;
     while let Some(message) = client.receive_message(ServerChannel::NetworkFrame.id()) {
-        let frame: NetworkFrame = bincode::deserialize(&message).unwrap();
-        match most_recent_tick.0 {
-            None => most_recent_tick.0 = Some(frame.tick),
-            Some(tick) if tick < frame.tick => most_recent_tick.0 = Some(frame.tick),
-            _ => continue,
+        bandwidth.count("Network frames", message.len());
+        let frame = match NetworkFrame::from_bytes(&message) {
+            Ok(frame) => frame,
+            Err(_) => continue,
+        };
+        let frame = match received_frames.assembler.receive(frame) {
+            Some(frame) => frame,
+            None => continue,
+        };
+        let CompleteFrame { tick, last_processed_input, snapshot: world_snapshot } = frame;
+
+        most_recent_tick.0 = Some(tick);
+        received_frames.last_frame_at = time.seconds_since_startup();
+        received_frames.server_clock.update(tick, time.seconds_since_startup());
+
+        // The server has applied these inputs, so they are already part of its position for us
+        if let Some(last_processed_input) = last_processed_input {
+            while pending_inputs.0.front().is_some_and(|input| input.sequence <= last_processed_input) {
+                pending_inputs.0.pop_front();
+            }
         }
 
-        for i in 0..frame.entities.entities.len() {
-            if let Some(entity) = network_mapping.0.get(&frame.entities.entities[i]) {
-                let translation = frame.entities.translations[i].into();
-                let transform = Transform {
-                    translation,
-                    ..Default::default()
+        let controlled_player = lobby.players.get(&client_id);
+        for (server_entity, translation) in world_snapshot.translations.iter() {
+            let translation = dequantize(*translation);
+            if let Some(player_info) = controlled_player.filter(|player_info| player_info.server_entity == *server_entity) {
+                // Start over from where the server has us and replay what it hasn't seen yet
+                if let Ok(mut transform) = controlled_player_query.get_single_mut() {
+                    transform.translation = translation;
+                    if !player_info.dead {
+                        let speed = assets.classes.get(player_info.class).speed;
+                        for input in pending_inputs.0.iter() {
+                            move_player(&mut transform.translation, input, speed, &arena);
+                        }
+                    }
+                }
+                continue;
+            }
+
+            if let Some(entity) = network_mapping.0.get(server_entity) {
+                let snapshot = Snapshot { tick, translation };
+
+                match snapshot_buffers.get_mut(*entity) {
+                    Ok(mut snapshot_buffer) => snapshot_buffer.push(snapshot),
+                    Err(_) => {
+                        let mut snapshot_buffer = SnapshotBuffer::default();
+                        snapshot_buffer.push(snapshot);
+                        commands.entity(*entity).insert(snapshot_buffer);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Moves remote entities to where they were `INTERPOLATION_DELAY` ago on the server
+fn interpolate_remote_entities(
+    time: Res<Time>,
+    received_frames: Res<ReceivedFrames>,
+    mut query: Query<(&mut Transform, &SnapshotBuffer)>,
+) {
+    let render_time = match received_frames.server_clock.now(time.seconds_since_startup()) {
+        Some(server_time) => server_time - INTERPOLATION_DELAY,
+        None => return,
+    };
+    for (mut transform, snapshot_buffer) in query.iter_mut() {
+        if let Some(translation) = snapshot_buffer.sample(render_time) {
+            transform.translation = translation;
+        }
+    }
+}
+
+fn pulse_effects(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut pulses: Query<(Entity, &mut PulseEffect, &mut Transform)>,
+) {
+    for (entity, mut pulse, mut transform) in pulses.iter_mut() {
+        pulse.0.tick(time.delta());
+        if pulse.0.finished() {
+            commands.entity(entity).despawn();
+        } else {
+            transform.scale = Vec3::splat(pulse.0.percent());
+        }
+    }
+}
+
+/// Shows our level and XP, and the upgrade choices whenever we level up
+fn progress_ui(
+    mut egui_context: ResMut<EguiContext>,
+    progress: Res<PlayerProgress>,
+    mut upgrade_choices: ResMut<UpgradeChoices>,
+    chest_upgrade: Res<ChestUpgrade>,
+    time: Res<Time>,
+    mut player_commands: EventWriter<PlayerCommand>,
+) {
+    egui::Area::new("progress")
+        .anchor(egui::Align2::LEFT_TOP, egui::vec2(8.0, 8.0))
+        .show(egui_context.ctx_mut(), |ui| {
+            ui.label(format!("Level {}", progress.level + 1));
+            ui.add(
+                egui::ProgressBar::new(progress.xp as f32 / progress.xp_needed.max(1) as f32)
+                    .desired_width(120.0)
+                    .text(format!("{} / {} XP", progress.xp, progress.xp_needed)),
+            );
+            if let Some((upgrade, received_at)) = &chest_upgrade.0 {
+                if time.seconds_since_startup() - received_at < CHEST_UPGRADE_TIME {
+                    ui.label(format!("Chest: {} level {}", upgrade.name, upgrade.level));
+                }
+            }
+        });
+
+    if upgrade_choices.0.is_empty() {
+        return;
+    }
+
+    let mut chosen = None;
+    egui::Window::new("Level up!")
+        .anchor(egui::Align2::CENTER_CENTER, egui::Vec2::ZERO)
+        .collapsible(false)
+        .resizable(false)
+        .show(egui_context.ctx_mut(), |ui| {
+            for (i, upgrade) in upgrade_choices.0.iter().enumerate() {
+                let title = if upgrade.level == 1 {
+                    format!("{} (new)", upgrade.name)
+                } else {
+                    format!("{} level {}", upgrade.name, upgrade.level)
                 };
-                commands.entity(*entity).insert(transform);
+                if ui.button(title).on_hover_text(&upgrade.description).clicked() {
+                    chosen = Some(i);
+                }
+            }
+        });
+
+    if let Some(choice) = chosen {
+        player_commands.send(PlayerCommand::ChooseUpgrade { choice });
+        upgrade_choices.0.clear();
+    }
+}
+
+/// Lets the player pick a class before connecting, and connects with it
+fn class_selection_ui(
+    mut commands: Commands,
+    mut egui_context: ResMut<EguiContext>,
+    class_selection: Option<Res<ClassSelection>>,
+    classes: Res<CharacterClasses>,
+    weapon_definitions: Res<WeaponDefinitions>,
+) {
+    let class_selection = match class_selection {
+        Some(class_selection) => class_selection,
+        None => return,
+    };
+
+    let mut chosen = None;
+    egui::Window::new("Choose your class")
+        .anchor(egui::Align2::CENTER_CENTER, egui::Vec2::ZERO)
+        .collapsible(false)
+        .resizable(false)
+        .show(egui_context.ctx_mut(), |ui| {
+            for (i, class) in classes.0.iter().enumerate() {
+                ui.heading(&class.name);
+                ui.label(&class.description);
+                ui.label(format!(
+                    "Speed {}, health {}, starts with {}",
+                    class.speed, class.health, weapon_definitions.0[class.starting_weapon].name
+                ));
+                if ui.button(format!("Play as {}", class.name)).clicked() {
+                    chosen = Some(i);
+                }
+                ui.separator();
             }
+        });
+
+    if let Some(class) = chosen {
+        match new_renet_client(&class_selection.username, class, class_selection.server_addr) {
+            Ok(client) => {
+                commands.insert_resource(client);
+                commands.remove_resource::<ClassSelection>();
+            }
+            Err(err) => println!("Could not connect: {}", err),
+        }
+    }
+}
+
+/// The lobby with ready toggles and the difficulty, the countdown, and the results after a game over
+fn match_ui(
+    mut egui_context: ResMut<EguiContext>,
+    client: Res<RenetClient>,
+    lobby: Res<ClientLobby>,
+    time: Res<Time>,
+    mut player_commands: EventWriter<PlayerCommand>,
+) {
+    let client_id = client.client_id();
+    let is_host = lobby.host == Some(client_id);
+
+    match &lobby.phase {
+        None | Some(MatchPhase::Running) => {}
+        Some(MatchPhase::Countdown { seconds }) => {
+            let seconds_left = (*seconds as f64 - (time.seconds_since_startup() - lobby.phase_received_at)).max(0.0);
+            egui::Area::new("countdown")
+                .anchor(egui::Align2::CENTER_TOP, egui::vec2(0.0, 48.0))
+                .show(egui_context.ctx_mut(), |ui| {
+                    ui.heading(format!("Starting in {}", seconds_left.ceil()));
+                });
+        }
+        Some(phase) => {
+            let title = match phase {
+                MatchPhase::GameOver { .. } => "Game over",
+                _ => "Lobby",
+            };
+
+            egui::Window::new(title)
+                .anchor(egui::Align2::CENTER_CENTER, egui::Vec2::ZERO)
+                .collapsible(false)
+                .resizable(false)
+                .show(egui_context.ctx_mut(), |ui| {
+                    if let MatchPhase::GameOver { results } = phase {
+                        let mut results = results.clone();
+                        results.sort_by(|a, b| b.survival_time.partial_cmp(&a.survival_time).unwrap());
+
+                        egui::Grid::new("results").striped(true).show(ui, |ui| {
+                            ui.strong("Player");
+                            ui.strong("Survived");
+                            ui.strong("Kills");
+                            ui.end_row();
+                            for result in results.iter() {
+                                ui.label(lobby.username(result.id));
+                                ui.label(format!("{}:{:02}", result.survival_time as u32 / 60, result.survival_time as u32 % 60));
+                                ui.label(result.kills.to_string());
+                                ui.end_row();
+                            }
+                        });
+                        ui.separator();
+                    }
+
+                    let mut ids: Vec<&u64> = lobby.players.keys().collect();
+                    ids.sort();
+                    for id in ids {
+                        let mut line = lobby.username(*id);
+                        if lobby.host == Some(*id) {
+                            line.push_str(" (host)");
+                        }
+                        if lobby.ready.contains(id) {
+                            line.push_str(" - ready");
+                        }
+                        ui.label(line);
+                    }
+                    ui.separator();
+
+                    ui.add_enabled_ui(is_host, |ui| {
+                        egui::ComboBox::from_label("Difficulty")
+                            .selected_text(format!("{:?}", lobby.difficulty))
+                            .show_ui(ui, |ui| {
+                                for difficulty in Difficulty::ALL {
+                                    if ui.selectable_label(lobby.difficulty == difficulty, format!("{:?}", difficulty)).clicked() {
+                                        player_commands.send(PlayerCommand::SetDifficulty { difficulty });
+                                    }
+                                }
+                            });
+                    });
+
+                    let mut ready = lobby.ready.contains(&client_id);
+                    if ui.checkbox(&mut ready, "Ready").changed() {
+                        player_commands.send(PlayerCommand::SetReady { ready });
+                    }
+                });
         }
     }
 }
 
+/// renet's graphs, and our own numbers per channel, while F3 is toggled on
+fn network_overlay(
+    mut egui_context: ResMut<EguiContext>,
+    mut visualizer: ResMut<RenetClientVisualizer<200>>,
+    mut bandwidth: ResMut<ChannelBandwidth>,
+    mut visible: Local<bool>,
+    client: Res<RenetClient>,
+    keyboard_input: Res<Input<KeyCode>>,
+    most_recent_tick: Res<MostRecentTick>,
+    received_frames: Res<ReceivedFrames>,
+    time: Res<Time>,
+) {
+    let network_info = client.network_info();
+    visualizer.add_network_info(network_info);
+
+    let now = time.seconds_since_startup();
+    let elapsed = now - bandwidth.counting_since;
+    if elapsed >= 1.0 {
+        let mut kbps: Vec<(&'static str, f32)> = bandwidth
+            .bytes
+            .drain()
+            .map(|(channel, bytes)| (channel, (bytes * 8) as f32 / 1000.0 / elapsed as f32))
+            .collect();
+        kbps.sort_by(|a, b| a.0.cmp(b.0));
+        bandwidth.kbps = kbps;
+        bandwidth.counting_since = now;
+    }
+
+    if keyboard_input.just_pressed(KeyCode::F3) {
+        *visible = !*visible;
+    }
+    if !*visible {
+        return;
+    }
+
+    visualizer.show_window(egui_context.ctx_mut());
+
+    // The server is half a round trip, plus however long ago the last frame came in, ahead of the last frame we have
+    let ticks_behind = most_recent_tick.0.map(|_| {
+        let seconds_behind = network_info.rtt as f64 / 2000.0 + (now - received_frames.last_frame_at);
+        (seconds_behind * TICK_RATE as f64).round() as u32
+    });
+
+    egui::Window::new("Network")
+        .anchor(egui::Align2::RIGHT_TOP, egui::vec2(-8.0, 8.0))
+        .resizable(false)
+        .show(egui_context.ctx_mut(), |ui| {
+            egui::Grid::new("network").show(ui, |ui| {
+                ui.label("RTT");
+                ui.label(format!("{:.0} ms", network_info.rtt));
+                ui.end_row();
+                ui.label("Packet loss");
+                ui.label(format!("{:.1}%", network_info.packet_loss * 100.0));
+                ui.end_row();
+                for (channel, kbps) in bandwidth.kbps.iter() {
+                    ui.label(*channel);
+                    ui.label(format!("{:.1} kbps", kbps));
+                    ui.end_row();
+                }
+                ui.label("Last tick");
+                ui.label(most_recent_tick.0.map_or("-".to_string(), |tick| tick.to_string()));
+                ui.end_row();
+                ui.label("Ticks behind server");
+                ui.label(ticks_behind.map_or("-".to_string(), |ticks| ticks.to_string()));
+                ui.end_row();
+            });
+        });
+}
+
+/// Everybody in the game, while Tab is held
+fn scoreboard_ui(mut egui_context: ResMut<EguiContext>, keyboard_input: Res<Input<KeyCode>>, lobby: Res<ClientLobby>) {
+    if !keyboard_input.pressed(KeyCode::Tab) {
+        return;
+    }
+
+    let mut players: Vec<&PlayerInfo> = lobby.players.values().collect();
+    players.sort_by(|a, b| a.username.cmp(&b.username));
+
+    egui::Window::new("Players")
+        .anchor(egui::Align2::CENTER_TOP, egui::vec2(0.0, 48.0))
+        .collapsible(false)
+        .resizable(false)
+        .show(egui_context.ctx_mut(), |ui| {
+            egui::Grid::new("scoreboard").striped(true).show(ui, |ui| {
+                for player_info in players {
+                    ui.label(&player_info.username);
+                    ui.label(if player_info.dead { "dead" } else { "alive" });
+                    ui.end_row();
+                }
+            });
+        });
+}
+
+fn health_bar_sprite(fraction: f32) -> Sprite {
+    Sprite {
+        color: Color::hex("98971a").unwrap(),
+        custom_size: Some(Vec2::new(HEALTH_BAR_WIDTH * fraction, 4.0)),
+        anchor: Anchor::CenterLeft,
+        ..Default::default()
+    }
+}
+
+/// We don't have any enemy sprites yet, so enemies are colored squares
+fn enemy_sprite(kind: EnemyKind) -> Sprite {
+    let color = match kind {
+        EnemyKind::Bat => Color::hex("b16286").unwrap(),
+        EnemyKind::Zombie => Color::hex("98971a").unwrap(),
+        EnemyKind::Ghoul => Color::hex("cc241d").unwrap(),
+    };
+
+    Sprite {
+        color,
+        custom_size: Some(Vec2::splat(kind.size())),
+        ..Default::default()
+    }
+}
+
+/// The color the sprites of a class are tinted with
+fn class_tint(class: &CharacterClass) -> Color {
+    Color::hex(&class.tint).unwrap_or(Color::WHITE)
+}
+
+fn pickup_sprite(pickup: Pickup) -> Sprite {
+    let (color, size) = match pickup {
+        Pickup::XpGem { .. } => ("8ec07c", 6.0),
+        Pickup::HealthPotion { .. } => ("fb4934", 10.0),
+        Pickup::Magnet => ("83a598", 10.0),
+        Pickup::Chest => ("d79921", 18.0),
+    };
+
+    Sprite {
+        color: Color::hex(color).unwrap(),
+        custom_size: Some(Vec2::splat(size)),
+        ..Default::default()
+    }
+}
+
 fn setup_camera(mut commands: Commands) {
     commands.spawn_bundle(Camera2dBundle {
         transform: Transform::from_xyz(0.0, 0.0, 1000.0),
@@ -241,23 +1164,63 @@ fn setup_camera(mut commands: Commands) {
     });
 }
 
+/// Draws the floor of the arena with the walls on top, below everything else
+fn setup_arena(mut commands: Commands, arena: Res<Arena>) {
+    commands.spawn_bundle(SpriteBundle {
+        sprite: Sprite {
+            color: Color::hex("32302f").unwrap(),
+            custom_size: Some(arena.size()),
+            ..Default::default()
+        },
+        transform: Transform::from_xyz(0.0, 0.0, -0.9),
+        ..Default::default()
+    });
+
+    for (center, tile) in arena.tiles() {
+        if tile != Tile::Wall {
+            continue;
+        }
+
+        commands.spawn_bundle(SpriteBundle {
+            sprite: Sprite {
+                color: Color::hex("504945").unwrap(),
+                custom_size: Some(Vec2::splat(TILE_SIZE)),
+                ..Default::default()
+            },
+            transform: Transform::from_translation(center.extend(-0.8)),
+            ..Default::default()
+        });
+    }
+}
+
 fn camera_follow(
-    mut camera_query: Query<&mut Transform, (With<Camera>, Without<ControlledPlayer>)>,
-    player_query: Query<&Transform, With<ControlledPlayer>>,
+    client: Res<RenetClient>,
+    lobby: Res<ClientLobby>,
+    mut camera_query: Query<&mut Transform, With<Camera>>,
+    player_query: Query<&Transform, Without<Camera>>,
 ) {
     let mut cam_transform = camera_query.single_mut();
-    if let Ok(player_transform) = player_query.get_single() {
+
+    // Follow our own player, or spectate one of the survivors while we wait to respawn
+    let followed_player = match lobby.players.get(&client.client_id()) {
+        Some(player_info) if player_info.dead => lobby.players.values().find(|player_info| !player_info.dead),
+        player_info => player_info,
+    };
+
+    if let Some(player_transform) = followed_player.and_then(|player_info| player_query.get(player_info.client_entity).ok()) {
         cam_transform.translation = player_transform.translation;
     }
 }
 
 fn disconnect(
     mut events: EventReader<AppExit>,
-    mut client: ResMut<RenetClient>,
+    client: Option<ResMut<RenetClient>>,
 ) {
     if let Some(_) = events.iter().next() {
         print!("Exiting...");
-        client.disconnect();
+        if let Some(mut client) = client {
+            client.disconnect();
+        }
         std::process::exit(0);
     }
 }
@@ -1,35 +1,288 @@
-use std::{env, f32};
-use std::collections::HashMap;
-use std::net::{SocketAddr, UdpSocket};
-use std::time::SystemTime;
+use std::f32;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime};
 
-use bevy::app::{App, PluginGroup, PluginGroupBuilder};
+use bevy::app::{App, PluginGroup, PluginGroupBuilder, ScheduleRunnerSettings};
 use bevy::diagnostic::{FrameTimeDiagnosticsPlugin, LogDiagnosticsPlugin};
+use bevy::ecs::schedule::{RunCriteria, RunCriteriaLabel, ShouldRun};
 use bevy::prelude::*;
+use bevy::time::FixedTimestep;
 use bevy_renet::RenetServerPlugin;
+use clap::{CommandFactory, Parser};
+use clap::builder::FalseyValueParser;
+use clap::error::ErrorKind;
+use rand::Rng;
+use rand::seq::SliceRandom;
 use renet::{NETCODE_USER_DATA_BYTES, RenetServer, ServerAuthentication, ServerConfig, ServerEvent};
+use serde::Deserialize;
 
-use vampire_surviors_clone::{AMOUNT_PLAYERS, ClientChannel, MaxSpeed, NetworkFrame, Player, PLAYER_SPEED, PlayerCommand, PlayerInput, PORT, PROTOCOL_ID, server_connection_config, ServerChannel, ServerMessages, translate_host, translate_port, Velocity, VERSION};
+use vampire_surviors_clone::{AMOUNT_PLAYERS, ClientChannel, COUNTDOWN_TIME, Difficulty, Enemy, EnemyKind, Health, MatchPhase, MaxSpeed, move_player, Pickup, PICKUP_RADIUS, Player, PLAYER_RADIUS, PlayerCommand, PlayerInput, PlayerResult, PORT, PRIVATE_KEY, Projectile, PROJECTILE_DAMAGE, PROJECTILE_LIFETIME, PROJECTILE_RADIUS, PROJECTILE_SPEED, PROTOCOL_ID, RESPAWN_TIME, server_connection_config, ServerChannel, ServerMessages, TICK_RATE, Velocity, VERSION};
+use vampire_surviors_clone::arena::{Arena, ARENA_PATH};
+use vampire_surviors_clone::auth::MAX_USERNAME_BYTES;
+use vampire_surviors_clone::classes::{CharacterClasses, CLASSES_PATH};
+use vampire_surviors_clone::config::{parse_host, read_config_file, validate_port};
+use vampire_surviors_clone::netsim::{NetworkConditions, NetworkSimulator};
+use vampire_surviors_clone::snapshot::WorldSnapshot;
+use vampire_surviors_clone::spatial::SpatialGrid;
+use vampire_surviors_clone::weapons::{UpgradeChoice, WeaponBehaviour, WeaponDefinitions, WEAPONS_PATH, xp_needed};
 
 /// Utility function for extracting a players name from renet user data
 fn name_from_user_data(user_data: &[u8; NETCODE_USER_DATA_BYTES]) -> String {
     let mut buffer = [0u8; 8];
     buffer.copy_from_slice(&user_data[0..8]);
     let mut len = u64::from_le_bytes(buffer) as usize;
-    len = len.min(NETCODE_USER_DATA_BYTES - 8);
-    let data = user_data[8..len + 8].to_vec();
-    String::from_utf8(data).unwrap()
+    len = len.min(MAX_USERNAME_BYTES);
+    // The user data comes straight from the client, so it doesn't have to be valid UTF-8
+    String::from_utf8_lossy(&user_data[8..len + 8]).into_owned()
 }
 
-fn translate_amount_players(amount_players: &str) -> usize {
-    amount_players.parse::<usize>().unwrap_or(AMOUNT_PLAYERS)
+/// The index of the class the player picked, which is the last byte of the user data
+fn class_from_user_data(user_data: &[u8; NETCODE_USER_DATA_BYTES]) -> usize {
+    user_data[NETCODE_USER_DATA_BYTES - 1] as usize
+}
+
+/// Options for the server, taken from the command line, then environment variables, then the config file
+#[derive(Debug, Default, Parser, Deserialize)]
+#[command(name = "server", version = VERSION, about = "Hosts a match of the vampire survivors clone")]
+#[serde(default, deny_unknown_fields)]
+struct ServerArgs {
+    /// JSON file with defaults for any of the other options, named like the long flags with underscores
+    #[arg(short, long, env = "SERVER_CONFIG")]
+    #[serde(skip)]
+    config: Option<PathBuf>,
+    /// Amount of players that can be connected at the same time [default: 4]
+    #[arg(short, long, env = "MAX_PLAYERS")]
+    players: Option<usize>,
+    /// Port to listen on [default: 5000]
+    #[arg(long, env = "SERVER_PORT")]
+    port: Option<u16>,
+    /// IP address to listen on, clients have to connect to exactly this address [default: 127.0.0.1]
+    #[arg(long, env = "SERVER_HOST")]
+    host: Option<String>,
+    /// Network frames sent to every client per second, at most one per tick [default: 20]
+    #[arg(long, env = "NETWORK_RATE")]
+    network_rate: Option<f64>,
+    /// Let clients in without a connect token from the token binary
+    #[arg(long, env = "UNSECURE", value_parser = FalseyValueParser::new())]
+    unsecure: bool,
+    /// Print the network stats of every client every few seconds
+    #[arg(long, env = "NETWORK_STATS", value_parser = FalseyValueParser::new())]
+    network_stats: bool,
+    /// Put a simulated bad network in front of the server, like latency=100,jitter=20,loss=5,duplicate=1
+    /// with the latency and jitter in milliseconds and the loss and duplication in percent
+    #[arg(long, env = "NETSIM")]
+    netsim: Option<String>,
+}
+
+/// The server options once everything has been filled in and checked
+struct ServerOptions {
+    amount_of_players: usize,
+    server_addr: SocketAddr,
+    network_rate: f64,
+    secure: bool,
+    network_stats: bool,
+    netsim: Option<NetworkConditions>,
+}
+
+impl ServerArgs {
+    /// Fills in whatever wasn't given from the config file and the defaults, exiting with an error
+    /// when any of it doesn't make sense
+    fn options(self) -> ServerOptions {
+        self.resolve().unwrap_or_else(|message| ServerArgs::command().error(ErrorKind::ValueValidation, message).exit())
+    }
+
+    fn resolve(self) -> Result<ServerOptions, String> {
+        let file: ServerArgs = match &self.config {
+            Some(path) => read_config_file(path).map_err(|err| format!("{:#}", err))?,
+            None => ServerArgs::default(),
+        };
+
+        let amount_of_players = self.players.or(file.players).unwrap_or(AMOUNT_PLAYERS);
+        if amount_of_players == 0 {
+            return Err("The server needs room for at least one player".to_string());
+        }
+
+        let port = validate_port(self.port.or(file.port).unwrap_or(PORT))?;
+        let host = parse_host(self.host.or(file.host).as_deref().unwrap_or("127.0.0.1"))?;
+
+        let network_rate = self.network_rate.or(file.network_rate).unwrap_or(DEFAULT_NETWORK_RATE);
+        if !(network_rate > 0.0 && network_rate <= TICK_RATE as f64) {
+            return Err(format!("The network rate has to be above 0 and at most the tick rate of {}", TICK_RATE));
+        }
+
+        let netsim = match self.netsim.or(file.netsim) {
+            Some(spec) => Some(spec.parse::<NetworkConditions>().map_err(|err| format!("Invalid network conditions: {}", err))?),
+            None => None,
+        };
+
+        Ok(ServerOptions {
+            amount_of_players,
+            server_addr: SocketAddr::new(host, port),
+            network_rate,
+            secure: !(self.unsecure || file.unsecure),
+            network_stats: self.network_stats || file.network_stats,
+            netsim,
+        })
+    }
 }
 
 #[derive(Debug, Default)]
 pub struct ServerLobby {
     pub players: HashMap<u64, Entity>,
+    pub usernames: HashMap<u64, String>,
+    /// Client ids in the order they connected, so the host can be handed to whoever has been around longest
+    pub join_order: Vec<u64>,
+    /// The player that gets to pick the difficulty
+    pub host: Option<u64>,
+    pub ready: HashSet<u64>,
+    pub difficulty: Difficulty,
+}
+
+impl ServerLobby {
+    fn message(&self) -> Vec<u8> {
+        bincode::serialize(&ServerMessages::Lobby {
+            host: self.host,
+            difficulty: self.difficulty,
+            ready: self.ready.iter().copied().collect(),
+        })
+            .unwrap()
+    }
+}
+
+#[derive(Debug)]
+enum MatchState {
+    Lobby,
+    Countdown(Timer),
+    Running,
+    GameOver,
+}
+
+#[derive(Debug, Default)]
+struct PlayerStats {
+    /// Ticks the player was alive for during the match
+    survival_ticks: u32,
+    kills: u32,
+}
+
+#[derive(Debug)]
+struct Match {
+    state: MatchState,
+    /// Stats of the current match, or of the last one after a game over
+    stats: HashMap<u64, PlayerStats>,
+}
+
+impl Default for Match {
+    fn default() -> Self {
+        Self {
+            state: MatchState::Lobby,
+            stats: HashMap::new(),
+        }
+    }
+}
+
+impl Match {
+    fn phase(&self) -> MatchPhase {
+        match &self.state {
+            MatchState::Lobby => MatchPhase::Lobby,
+            MatchState::Countdown(timer) => MatchPhase::Countdown {
+                seconds: timer.duration().as_secs_f32() - timer.elapsed_secs(),
+            },
+            MatchState::Running => MatchPhase::Running,
+            MatchState::GameOver => MatchPhase::GameOver {
+                results: self
+                    .stats
+                    .iter()
+                    .map(|(id, stats)| PlayerResult {
+                        id: *id,
+                        survival_time: stats.survival_ticks as f32 * TICK_DURATION.as_secs_f32(),
+                        kills: stats.kills,
+                    })
+                    .collect(),
+            },
+        }
+    }
+
+    fn phase_message(&self) -> Vec<u8> {
+        bincode::serialize(&ServerMessages::MatchPhase { phase: self.phase() }).unwrap()
+    }
+}
+
+/// Seconds between two printouts of the network stats
+const NETWORK_STATS_INTERVAL: f32 = 5.0;
+
+struct NetworkStatsLog(Timer);
+
+/// Seconds between two `ServerMessages::ServerStats`
+const SERVER_STATS_INTERVAL: f32 = 1.0;
+
+/// How long frames took since the last `ServerMessages::ServerStats` was sent
+struct FrameTimes {
+    frame_started: Instant,
+    total: Duration,
+    max: Duration,
+    frames: u32,
+    tick_at_last_report: u32,
+    report: Timer,
+}
+
+impl Default for FrameTimes {
+    fn default() -> Self {
+        Self {
+            frame_started: Instant::now(),
+            total: Duration::ZERO,
+            max: Duration::ZERO,
+            frames: 0,
+            tick_at_last_report: 0,
+            report: Timer::from_seconds(SERVER_STATS_INTERVAL, true),
+        }
+    }
+}
+
+/// Sent when a countdown starts, to clear out the last match
+struct ResetWorld;
+
+/// Projectiles that hit something or ran out this tick. A projectile can do both in the same tick,
+/// so they are collected here and despawned once at the end of it.
+#[derive(Debug, Default)]
+struct SpentProjectiles(HashSet<Entity>);
+
+/// The steps of a tick, in the order they run. Without a fixed order Bevy is free to run the systems in a
+/// different order every frame, and the same inputs wouldn't always lead to the same result.
+#[derive(SystemLabel, Debug, Clone, PartialEq, Eq, Hash)]
+enum TickStep {
+    Advance,
+    Match,
+    Reset,
+    MovePlayers,
+    Waves,
+    MoveEnemies,
+    Weapons,
+    Blades,
+    MoveProjectiles,
+    ProjectileLifetime,
+    ProjectileHits,
+    BladeHits,
+    ContactDamage,
+    EnemyDeaths,
+    PlayerDeaths,
+    Respawns,
+    Magnet,
+    Pickups,
+    Upgrades,
+    Cleanup,
 }
 
+/// The player that gets credited with the kill when an enemy dies
+#[derive(Debug, Default, Component)]
+struct LastHitBy(Option<u64>);
+
+const TICK_DURATION: Duration = Duration::from_nanos(1_000_000_000 / TICK_RATE);
+/// Frames sent to the clients per second, unless overridden with `NETWORK_RATE`
+const DEFAULT_NETWORK_RATE: f64 = 20.0;
+
+/// The current simulation tick
 #[derive(Debug, Default)]
 struct NetworkTick(u32);
 
@@ -37,47 +290,472 @@ struct NetworkTick(u32);
 #[derive(Debug, Default)]
 struct ClientTicks(HashMap<u64, Option<u32>>);
 
-fn new_renet_server(amount_of_player: usize, host: &str, port: i32) -> RenetServer {
-    let server_addr: SocketAddr = format!("{}:{}", host, port)
-        .parse()
-        .unwrap();
-    let socket = UdpSocket::bind(server_addr).unwrap();
+/// How many of the last sent snapshots are kept around to encode frames against
+const SNAPSHOT_HISTORY: usize = 64;
+
+/// The snapshots we sent to each client recently, oldest first
+#[derive(Debug, Default)]
+struct SnapshotHistory(HashMap<u64, VecDeque<(u32, WorldSnapshot)>>);
+
+/// Entities within this distance of a player are sent to them
+const RELEVANCE_RADIUS: f32 = 600.0;
+/// Entities are only removed once they are this much further away, so they don't flicker at the edge
+const RELEVANCE_MARGIN: f32 = 100.0;
+const GRID_CELL_SIZE: f32 = 200.0;
+
+/// An entity that is only sent to the clients it is relevant to. Players are always sent to everybody.
+#[derive(Debug, Clone, Copy, Component)]
+enum Replicated {
+    Projectile,
+    Enemy(EnemyKind),
+    Blade,
+    Pickup(Pickup),
+}
+
+impl Replicated {
+    fn spawn_message(&self, entity: Entity, translation: Vec3) -> ServerMessages {
+        let translation = [translation.x, translation.y];
+        match *self {
+            Self::Projectile => ServerMessages::SpawnProjectile { entity, translation },
+            Self::Enemy(kind) => ServerMessages::SpawnEnemy { entity, kind, translation },
+            Self::Blade => ServerMessages::SpawnBlade { entity, translation },
+            Self::Pickup(pickup) => ServerMessages::SpawnPickup { entity, pickup, translation },
+        }
+    }
+
+    fn despawn_message(&self, entity: Entity) -> ServerMessages {
+        match self {
+            Self::Projectile => ServerMessages::DespawnProjectile { entity },
+            Self::Enemy(_) => ServerMessages::DespawnEnemy { entity },
+            Self::Blade => ServerMessages::DespawnBlade { entity },
+            Self::Pickup(_) => ServerMessages::DespawnPickup { entity },
+        }
+    }
+}
+
+/// The replicated entities each client currently knows about
+#[derive(Debug, Default)]
+struct ReplicationSets(HashMap<u64, HashMap<Entity, Replicated>>);
+
+/// Inputs that can wait in an `InputQueue` before they are applied several at a time
+const MAX_BUFFERED_INPUTS: usize = 3;
+
+/// Inputs received from a player that haven't been applied yet
+#[derive(Debug, Default, Component)]
+struct InputQueue {
+    inputs: VecDeque<PlayerInput>,
+    /// Sequence number of the last input that was applied, sent back so the client can reconcile
+    last_processed: Option<u32>,
+}
+
+/// Inputs a client may send per second. A little more than one per tick, since the clocks of client and server
+/// never quite agree.
+const INPUTS_PER_SECOND: f32 = TICK_RATE as f32 * 1.1;
+const COMMANDS_PER_SECOND: f32 = 20.0;
+/// Seconds worth of messages a client may send at once, messages arrive in bursts after packet loss
+const MESSAGE_BURST: f32 = 0.5;
+/// Misbehaving messages a client can send before they are kicked, one is forgiven every second.
+/// Messages over the rate limit only count for a fraction, see `RateLimit::overrun_strike`.
+const MAX_STRIKES: f32 = 20.0;
+/// Time a kicked client gets to receive the reason before they are disconnected
+const KICK_DELAY: f32 = 0.5;
+
+/// Lets through `per_second` messages on average, saving up for bursts while a client is quiet
+#[derive(Debug)]
+struct RateLimit {
+    per_second: f32,
+    tokens: f32,
+}
+
+impl RateLimit {
+    fn new(per_second: f32) -> Self {
+        Self { per_second, tokens: per_second * MESSAGE_BURST }
+    }
+
+    fn refill(&mut self, seconds: f32) {
+        self.tokens = (self.tokens + seconds * self.per_second).min(self.per_second * MESSAGE_BURST);
+    }
+
+    fn take(&mut self) -> bool {
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+
+    /// How much of a strike a message over the limit is. Clients that catch up after a stall, or messages
+    /// resent after packet loss, go over the limit without anything being wrong, so it takes a second's worth
+    /// of extra messages to make a whole strike. Only a client that keeps sending too fast gets kicked.
+    fn overrun_strike(&self) -> f32 {
+        1.0 / self.per_second
+    }
+}
+
+/// Keeps an eye on what a client sends, so a broken or malicious client can't take the server down
+#[derive(Debug)]
+struct ClientGuard {
+    inputs: RateLimit,
+    commands: RateLimit,
+    last_sequence: Option<u32>,
+    strikes: f32,
+    /// Why the client is being kicked, and when they will be disconnected
+    kicked: Option<(String, Timer)>,
+}
+
+impl Default for ClientGuard {
+    fn default() -> Self {
+        Self {
+            inputs: RateLimit::new(INPUTS_PER_SECOND),
+            commands: RateLimit::new(COMMANDS_PER_SECOND),
+            last_sequence: None,
+            strikes: 0.0,
+            kicked: None,
+        }
+    }
+}
+
+impl ClientGuard {
+    /// Refills the rate limits and forgives old strikes
+    fn update(&mut self, seconds: f32) {
+        self.inputs.refill(seconds);
+        self.commands.refill(seconds);
+        self.strikes = (self.strikes - seconds).max(0.0);
+    }
+
+    /// Counts a message that was dropped, kicking the client when they have done this too often
+    fn strike(&mut self, reason: &str) {
+        self.add_strikes(1.0, reason);
+    }
+
+    fn add_strikes(&mut self, strikes: f32, reason: &str) {
+        self.strikes += strikes;
+        if self.strikes >= MAX_STRIKES && self.kicked.is_none() {
+            self.kicked = Some((reason.to_string(), Timer::from_seconds(KICK_DELAY, false)));
+        }
+    }
+
+    /// Deserializes a message from the command channel, or strikes the client if they shouldn't have sent it
+    fn accept_command(&mut self, message: &[u8]) -> Option<PlayerCommand> {
+        // Whatever a kicked client still has to say doesn't matter anymore
+        if self.kicked.is_some() {
+            return None;
+        }
+        if !self.commands.take() {
+            self.add_strikes(self.commands.overrun_strike(), "Sent too many commands");
+            return None;
+        }
+        match bincode::deserialize::<PlayerCommand>(message) {
+            Ok(command) if command_is_valid(&command) => Some(command),
+            _ => {
+                self.strike("Sent invalid commands");
+                None
+            }
+        }
+    }
+
+    /// Deserializes a message from the input channel, or strikes the client if they shouldn't have sent it.
+    /// `tick` is the current tick, clients can't have seen any frame after it.
+    fn accept_input(&mut self, message: &[u8], tick: u32) -> Option<PlayerInput> {
+        if self.kicked.is_some() {
+            return None;
+        }
+        // Applying more inputs than there are ticks would let a player move faster than everyone else
+        if !self.inputs.take() {
+            self.add_strikes(self.inputs.overrun_strike(), "Sent too many inputs");
+            return None;
+        }
+        let input: PlayerInput = match bincode::deserialize(message) {
+            Ok(input) => input,
+            Err(_) => {
+                self.strike("Sent invalid inputs");
+                return None;
+            }
+        };
+        if self.last_sequence.is_some_and(|last_sequence| input.sequence <= last_sequence) {
+            self.strike("Sent inputs out of order");
+            return None;
+        }
+        if input.most_recent_tick.is_some_and(|most_recent_tick| most_recent_tick > tick) {
+            self.strike("Acknowledged frames that were never sent");
+            return None;
+        }
+        self.last_sequence = Some(input.sequence);
+        Some(input)
+    }
+}
+
+#[derive(Debug, Default)]
+struct ClientGuards(HashMap<u64, ClientGuard>);
+
+/// Catches commands that deserialize fine but can't have come from an honest client
+fn command_is_valid(command: &PlayerCommand) -> bool {
+    match command {
+        PlayerCommand::BasicAttack { cast_at } => cast_at.is_finite(),
+        _ => true,
+    }
+}
+
+/// Index of the class a player joined as
+#[derive(Debug, Component)]
+struct PlayerClass(usize);
+
+/// Marks a player that has died and is spectating until they respawn
+#[derive(Debug, Component)]
+struct Dead {
+    respawn: Timer,
+}
+
+/// The health we last told the clients a player has
+#[derive(Debug, Component)]
+struct SyncedHealth(f32);
+
+#[derive(Debug)]
+struct EquippedWeapon {
+    /// Index into the `WeaponDefinitions`
+    weapon: usize,
+    /// Starts at 0, unlike the levels shown to players
+    level: usize,
+    cooldown: Timer,
+}
+
+impl EquippedWeapon {
+    fn new(weapon: usize, definitions: &WeaponDefinitions) -> Self {
+        Self {
+            weapon,
+            level: 0,
+            cooldown: Timer::from_seconds(definitions.level(weapon, 0).cooldown, true),
+        }
+    }
+}
+
+/// The weapons a player fires automatically
+#[derive(Debug, Component)]
+struct Weapons(Vec<EquippedWeapon>);
+
+impl Weapons {
+    /// Any weapon the player doesn't have yet, or the next level of one they have
+    fn available_upgrades(&self, weapon_definitions: &WeaponDefinitions) -> Vec<UpgradeChoice> {
+        weapon_definitions
+            .0
+            .iter()
+            .enumerate()
+            .filter_map(|(weapon, definition)| {
+                let next_level = match self.0.iter().find(|equipped| equipped.weapon == weapon) {
+                    Some(equipped) if equipped.level >= weapon_definitions.max_level(weapon) => return None,
+                    Some(equipped) => equipped.level + 1,
+                    None => 0,
+                };
+                Some(UpgradeChoice {
+                    weapon,
+                    name: definition.name.clone(),
+                    description: definition.description.clone(),
+                    level: next_level + 1,
+                })
+            })
+            .collect()
+    }
+
+    fn apply_upgrade(&mut self, upgrade: &UpgradeChoice, weapon_definitions: &WeaponDefinitions) {
+        match self.0.iter_mut().find(|equipped| equipped.weapon == upgrade.weapon) {
+            Some(equipped) => {
+                equipped.level += 1;
+                let cooldown = weapon_definitions.level(equipped.weapon, equipped.level).cooldown;
+                equipped.cooldown.set_duration(Duration::from_secs_f32(cooldown));
+            }
+            None => self.0.push(EquippedWeapon::new(upgrade.weapon, weapon_definitions)),
+        }
+    }
+}
+
+/// Pickups within this distance of a player are pulled towards them
+#[derive(Debug, Component)]
+struct MagnetRadius(f32);
+
+impl Default for MagnetRadius {
+    fn default() -> Self {
+        Self(BASE_MAGNET_RADIUS)
+    }
+}
+
+const BASE_MAGNET_RADIUS: f32 = 48.0;
+/// How much every magnet pickup adds to the magnet radius, up to the maximum
+const MAGNET_BONUS: f32 = 32.0;
+const MAX_MAGNET_RADIUS: f32 = 320.0;
+/// Units per second pickups fly towards the player pulling them in
+const PICKUP_PULL_SPEED: f32 = 250.0;
+const HEALTH_POTION_HEAL: f32 = 30.0;
+/// Chances of an enemy dropping something besides its XP gem
+const HEALTH_POTION_CHANCE: f64 = 0.02;
+const MAGNET_CHANCE: f64 = 0.005;
+/// Only ghouls drop chests
+const CHEST_CHANCE: f64 = 0.1;
+
+#[derive(Debug, Default, Component)]
+struct Experience {
+    xp: u32,
+    level: u32,
+    /// Level ups the player hasn't picked an upgrade for yet
+    pending_level_ups: u32,
+    /// The upgrades the player is currently choosing from
+    offered_upgrades: Vec<UpgradeChoice>,
+}
+
+/// A blade circling around its owner for a while
+#[derive(Debug, Component)]
+struct OrbitingBlade {
+    owner: Entity,
+    owner_id: u64,
+    angle: f32,
+    radius: f32,
+    /// Radians per second
+    angular_speed: f32,
+    damage: f32,
+    duration: Timer,
+    /// Blades hurt the enemies they touch every time this goes off
+    hit_timer: Timer,
+}
+
+/// Blades are drawn as 16x16 sprites
+const BLADE_RADIUS: f32 = 8.0;
+/// Seconds between two hits of the same blade
+const BLADE_HIT_INTERVAL: f32 = 0.25;
+/// Blades spin for this part of their weapon's cooldown, and are gone for the rest of it
+const BLADE_UPTIME: f32 = 0.6;
+
+/// Seconds between two waves of enemies
+const WAVE_INTERVAL: f32 = 5.0;
+/// Enemies spawn in a ring around the players, just outside of what they can see
+const SPAWN_RADIUS_MIN: f32 = 350.0;
+const SPAWN_RADIUS_MAX: f32 = 450.0;
+const SPAWN_ATTEMPTS: usize = 10;
+/// Waves stop spawning enemies once there are this many alive
+const MAX_ENEMIES: usize = 300;
+
+/// Sends out waves of enemies that grow larger and tougher over time
+#[derive(Debug)]
+struct WaveDirector {
+    timer: Timer,
+    wave: u32,
+    difficulty: Difficulty,
+}
+
+impl Default for WaveDirector {
+    fn default() -> Self {
+        Self {
+            timer: Timer::from_seconds(WAVE_INTERVAL, true),
+            wave: 0,
+            difficulty: Difficulty::default(),
+        }
+    }
+}
+
+impl WaveDirector {
+    /// How many enemies the current wave spawns around each player
+    fn enemies_per_player(&self) -> usize {
+        ((3 + self.wave as usize * 2) as f32 * self.difficulty.enemy_amount()).ceil() as usize
+    }
+
+    /// Picks the kind of the next enemy. Zombies show up from wave 3, ghouls from wave 6.
+    fn pick_kind(&self, rng: &mut impl Rng) -> EnemyKind {
+        let roll = rng.gen_range(0..100);
+        match self.wave {
+            0..=2 => EnemyKind::Bat,
+            3..=5 if roll < 30 => EnemyKind::Zombie,
+            3..=5 => EnemyKind::Bat,
+            _ if roll < 15 => EnemyKind::Ghoul,
+            _ if roll < 50 => EnemyKind::Zombie,
+            _ => EnemyKind::Bat,
+        }
+    }
+}
+
+/// The fixed timestep every tick system runs on
+#[derive(RunCriteriaLabel, Debug, Clone, PartialEq, Eq, Hash)]
+struct TickTimestep;
+
+/// The simulation steps by exactly one tick at a time, catching up with several steps when it falls behind.
+/// Players can walk around in the lobby, everything else waits for the match to start. A system set can only
+/// have one run criteria, so the match systems get their own set, piped from the same timestep so both sets
+/// always step together.
+fn add_tick_systems(app: &mut App) {
+    app.add_system_set(tick_systems().with_run_criteria(FixedTimestep::step(TICK_DURATION.as_secs_f64()).label(TickTimestep)));
+    app.add_system_set(match_tick_systems().with_run_criteria(RunCriteria::pipe(TickTimestep, match_running)));
+}
+
+/// The part of a tick that always runs
+fn tick_systems() -> SystemSet {
+    SystemSet::new()
+        .with_system(advance_tick_system.label(TickStep::Advance))
+        .with_system(match_system.label(TickStep::Match).after(TickStep::Advance))
+        .with_system(reset_world_system.label(TickStep::Reset).after(TickStep::Match))
+        .with_system(move_players_system.label(TickStep::MovePlayers).after(TickStep::Reset))
+}
+
+/// The part of a tick that only runs during a match, after `tick_systems`
+fn match_tick_systems() -> SystemSet {
+    SystemSet::new()
+        .with_system(wave_director_system.label(TickStep::Waves).after(TickStep::MovePlayers))
+        .with_system(enemy_chase_system.label(TickStep::MoveEnemies).after(TickStep::Waves))
+        .with_system(weapon_system.label(TickStep::Weapons).after(TickStep::MoveEnemies))
+        .with_system(orbit_blades_system.label(TickStep::Blades).after(TickStep::Weapons))
+        .with_system(move_projectiles_system.label(TickStep::MoveProjectiles).after(TickStep::Blades))
+        .with_system(projectile_lifetime_system.label(TickStep::ProjectileLifetime).after(TickStep::MoveProjectiles))
+        .with_system(projectile_hit_system.label(TickStep::ProjectileHits).after(TickStep::ProjectileLifetime))
+        .with_system(blade_hit_system.label(TickStep::BladeHits).after(TickStep::ProjectileHits))
+        .with_system(enemy_contact_damage_system.label(TickStep::ContactDamage).after(TickStep::BladeHits))
+        .with_system(enemy_death_system.label(TickStep::EnemyDeaths).after(TickStep::ContactDamage))
+        .with_system(player_death_system.label(TickStep::PlayerDeaths).after(TickStep::EnemyDeaths))
+        .with_system(player_respawn_system.label(TickStep::Respawns).after(TickStep::PlayerDeaths))
+        .with_system(magnet_system.label(TickStep::Magnet).after(TickStep::Respawns))
+        .with_system(collect_pickups_system.label(TickStep::Pickups).after(TickStep::Magnet))
+        .with_system(offer_upgrades_system.label(TickStep::Upgrades).after(TickStep::Pickups))
+        .with_system(despawn_spent_projectiles_system.label(TickStep::Cleanup).after(TickStep::Upgrades))
+}
+
+fn new_renet_server(amount_of_player: usize, server_addr: SocketAddr, secure: bool, netsim: Option<NetworkConditions>) -> RenetServer {
+    // When simulating a bad network, clients talk to the simulator on the server address and only the simulator
+    // talks to the actual server socket. Connect tokens keep pointing at the server address, so they stay valid.
+    let socket = match netsim {
+        Some(conditions) => {
+            let socket = UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0)).unwrap();
+            let simulator = NetworkSimulator::new(server_addr, socket.local_addr().unwrap(), conditions)
+                .expect("Could not start the network simulator");
+            simulator.spawn();
+            socket
+        }
+        None => UdpSocket::bind(server_addr).unwrap(),
+    };
     let connection_config = server_connection_config();
-    let server_config = ServerConfig::new(amount_of_player, PROTOCOL_ID, server_addr, ServerAuthentication::Unsecure);
+    // Secure servers only let in clients with a token from the token binary
+    let authentication = if secure {
+        ServerAuthentication::Secure { private_key: *PRIVATE_KEY }
+    } else {
+        ServerAuthentication::Unsecure
+    };
+    let server_config = ServerConfig::new(amount_of_player, PROTOCOL_ID, server_addr, authentication);
     let current_time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
     RenetServer::new(current_time, server_config, connection_config, socket).unwrap()
 }
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    let mut port = PORT;
-    let mut host = "127.0.0.1";
-    let mut amount_of_players = AMOUNT_PLAYERS;
-    match args.len() {
-        2 => {
-            amount_of_players = translate_amount_players(&args[1]);
-            println!("Amount of players set to: {}", amount_of_players);
-        }
-        3 => {
-            port = translate_port(&args[2]);
-            amount_of_players = translate_amount_players(&args[1]);
-            println!("Amount of players has been set to: {}, Port has been set to: {}", amount_of_players, port);
-        }
-        4 => {
-            host = translate_host(&args[3], "");
-            port = translate_port(&args[2]);
-            amount_of_players = translate_amount_players(&args[1]);
-            println!("Amount of players has been set to: {}, Port has been set to: {}, Host has been set to: {}", amount_of_players, port, host);
-        }
-        _ => {
-            println!("Usage: server [amount of players] [port] [host]");
-            println!("Default values: amount of players: {}, port: {}, host: {}", AMOUNT_PLAYERS, PORT, host);
-        }
-    };
+    let ServerOptions { amount_of_players, server_addr, network_rate, secure, network_stats, netsim } = ServerArgs::parse().options();
+
+    println!("Listening on {} for up to {} players", server_addr, amount_of_players);
+    println!("Simulating {} ticks per second, sending {} frames per second", TICK_RATE, network_rate);
+    if !secure {
+        println!("Running without authentication, clients can connect without a token");
+    }
+    if let Some(conditions) = netsim {
+        // Simulating a bad network makes it easy to see how the game holds up without leaving localhost
+        println!("Simulating a network with {:?}", conditions);
+    }
 
     println!("Version: {}", VERSION);
 
     let mut app = App::new();
+    // Don't spin faster than the simulation needs to
+    app.insert_resource(ScheduleRunnerSettings::run_loop(TICK_DURATION));
     app.add_plugins(ServerPlugins);
     app.add_plugin(RenetServerPlugin);
 
@@ -87,11 +765,41 @@ fn main() {
     app.insert_resource(ServerLobby::default());
     app.insert_resource(NetworkTick(0));
     app.insert_resource(ClientTicks::default());
-    app.insert_resource(new_renet_server(amount_of_players, host, port));
+    app.insert_resource(ClientGuards::default());
+    app.insert_resource(SnapshotHistory::default());
+    app.insert_resource(ReplicationSets::default());
+    app.insert_resource(Match::default());
+    app.insert_resource(FrameTimes::default());
+    app.insert_resource(SpentProjectiles::default());
+    if network_stats {
+        app.insert_resource(NetworkStatsLog(Timer::from_seconds(NETWORK_STATS_INTERVAL, true)));
+        app.add_system(network_stats_system);
+    }
+    app.add_event::<ResetWorld>();
+    app.insert_resource(WaveDirector::default());
+    let weapon_definitions = WeaponDefinitions::load(WEAPONS_PATH).expect("Could not load the weapon definitions");
+    app.insert_resource(CharacterClasses::load(CLASSES_PATH, &weapon_definitions).expect("Could not load the classes"));
+    app.insert_resource(weapon_definitions);
+    app.insert_resource(Arena::load(ARENA_PATH).expect("Could not load the arena"));
+    app.insert_resource(new_renet_server(amount_of_players, server_addr, secure, netsim));
+
+    // Inputs that came in this frame can be applied by the tick right after
+    app.add_system(server_update_system.before(TickStep::Advance));
+    app.add_system(client_guard_system);
+
+    add_tick_systems(&mut app);
 
-    app.add_system(server_update_system);
-    app.add_system(server_network_sync);
-    app.add_system(move_players_system);
+    app.add_system_set(
+        SystemSet::new()
+            .with_run_criteria(FixedTimestep::steps_per_second(network_rate))
+            .with_system(server_network_sync.after(TickStep::Cleanup)),
+    );
+    app.add_system(sync_player_health_system);
+    app.add_system(sync_experience_system);
+
+    // Everything else runs in between these two
+    app.add_system_to_stage(CoreStage::First, frame_start_system);
+    app.add_system_to_stage(CoreStage::Last, server_stats_system);
 
     app.run();
 }
@@ -103,122 +811,1430 @@ fn server_update_system(
     mut lobby: ResMut<ServerLobby>,
     mut server: ResMut<RenetServer>,
     mut client_ticks: ResMut<ClientTicks>,
-    players: Query<(Entity, &Player, &Transform)>,
+    mut guards: ResMut<ClientGuards>,
+    tick: Res<NetworkTick>,
+    players: Query<(Entity, &Player, &PlayerClass, &Transform, &Health, Option<&Dead>)>,
+    mut upgrades: Query<(&mut Experience, &mut Weapons)>,
+    mut input_queues: Query<&mut InputQueue>,
+    weapon_definitions: Res<WeaponDefinitions>,
+    classes: Res<CharacterClasses>,
+    current_match: Res<Match>,
 ) {
     for event in server_events.iter() {
         match event {
             ServerEvent::ClientConnected(id, user_data) => {
                 let username = name_from_user_data(&user_data);
-                println!("Player {} connected.", username);
+                // Clients that picked a class we don't know about get the first one
+                let class = Some(class_from_user_data(&user_data))
+                    .filter(|class| *class < classes.0.len())
+                    .unwrap_or(0);
+                let class_definition = classes.get(class);
+                println!("Player {} connected as {}.", username, class_definition.name);
 
                 // Initialize other players for this new client
-                for (entity, player, transform) in players.iter() {
+                for (entity, player, player_class, transform, health, dead) in players.iter() {
                     let translation: [f32; 3] = transform.translation.into();
                     let message = bincode::serialize(&ServerMessages::PlayerCreate {
                         id: player.id,
+                        username: lobby.usernames.get(&player.id).cloned().unwrap_or_default(),
+                        class: player_class.0,
                         entity,
                         translation: [translation[0], translation[1]],
                     })
                         .unwrap();
                     server.send_message(*id, ServerChannel::ServerMessages.id(), message);
+
+                    let message = bincode::serialize(&ServerMessages::PlayerHealth {
+                        id: player.id,
+                        current: health.current,
+                        max: health.max,
+                    })
+                        .unwrap();
+                    server.send_message(*id, ServerChannel::ServerMessages.id(), message);
+
+                    if dead.is_some() {
+                        let message = bincode::serialize(&ServerMessages::PlayerDied { id: player.id }).unwrap();
+                        server.send_message(*id, ServerChannel::ServerMessages.id(), message);
+                    }
                 }
 
+                // Enemies, projectiles and so on are sent by `server_network_sync` once they are close enough
+
+                let message = current_match.phase_message();
+                server.send_message(*id, ServerChannel::ServerMessages.id(), message);
+
                 // Spawn new player
                 let transform = Transform::from_xyz(0.0, 0.51, 0.0);
 
                 let player_entity = commands
                     .spawn()
                     .insert(Player { id: *id })
-                    .insert(PlayerInput::default())
+                    .insert(InputQueue::default())
                     .insert(transform)
-                    .insert(Velocity(Vec2::ZERO))
-                    .insert(MaxSpeed(PLAYER_SPEED))
+                    .insert(PlayerClass(class))
+                    .insert(MaxSpeed(class_definition.speed))
+                    .insert(Health::new(class_definition.health))
+                    .insert(SyncedHealth(class_definition.health))
+                    .insert(Weapons(vec![EquippedWeapon::new(class_definition.starting_weapon, &weapon_definitions)]))
+                    .insert(Experience::default())
+                    .insert(MagnetRadius::default())
                     .id();
 
                 lobby.players.insert(*id, player_entity);
+                lobby.usernames.insert(*id, username.clone());
+                lobby.join_order.push(*id);
+                guards.0.insert(*id, ClientGuard::default());
+                if lobby.host.is_none() {
+                    lobby.host = Some(*id);
+                }
 
                 let translation: [f32; 3] = transform.translation.into();
                 let message = bincode::serialize(&ServerMessages::PlayerCreate {
                     id: *id,
+                    username,
+                    class,
                     entity: player_entity,
                     translation: [translation[0], translation[1]],
                 })
                     .unwrap();
                 server.broadcast_message(ServerChannel::ServerMessages.id(), message);
+                server.broadcast_message(ServerChannel::ServerMessages.id(), lobby.message());
             }
             ServerEvent::ClientDisconnected(id) => {
-                println!("Player {} disconnected.", id);
+                match lobby.usernames.remove(id) {
+                    Some(username) => println!("Player {} disconnected.", username),
+                    None => println!("Player {} disconnected.", id),
+                }
                 client_ticks.0.remove(id);
+                guards.0.remove(id);
                 if let Some(player_entity) = lobby.players.remove(id) {
                     commands.entity(player_entity).despawn();
                 }
 
+                // The player that has been connected the longest gets to be the host
+                lobby.ready.remove(id);
+                lobby.join_order.retain(|joined| joined != id);
+                if lobby.host == Some(*id) {
+                    lobby.host = lobby.join_order.first().copied();
+                }
+
                 let message = bincode::serialize(&ServerMessages::PlayerRemove { id: *id }).unwrap();
                 server.broadcast_message(ServerChannel::ServerMessages.id(), message);
+                server.broadcast_message(ServerChannel::ServerMessages.id(), lobby.message());
             }
         }
     }
 
     for client_id in server.clients_id().into_iter() {
+        let guard = guards.0.entry(client_id).or_default();
         while let Some(message) = server.receive_message(client_id, ClientChannel::Command.id()) {
-            let command: PlayerCommand = bincode::deserialize(&message).unwrap();
+            let command = match guard.accept_command(&message) {
+                Some(command) => command,
+                None => continue,
+            };
+
             match command {
-                PlayerCommand::BasicAttack { .. } => {}
+                PlayerCommand::BasicAttack { cast_at } => {
+                    // Projectiles only fly during a match, outside of one they would just hang in the air
+                    if !matches!(current_match.state, MatchState::Running) {
+                        continue;
+                    }
+
+                    // Dead players can't attack
+                    let player_transform = lobby
+                        .players
+                        .get(&client_id)
+                        .and_then(|player_entity| players.get(*player_entity).ok())
+                        .filter(|(_, _, _, _, _, dead)| dead.is_none())
+                        .map(|(_, _, _, transform, _, _)| *transform);
+
+                    if let Some(player_transform) = player_transform {
+                        let direction = (cast_at - player_transform.translation).truncate().normalize_or_zero();
+                        if direction == Vec2::ZERO {
+                            continue;
+                        }
+
+                        let translation = player_transform.translation;
+                        commands
+                            .spawn()
+                            .insert(Projectile {
+                                owner: client_id,
+                                duration: Timer::from_seconds(PROJECTILE_LIFETIME, false),
+                                damage: PROJECTILE_DAMAGE,
+                            })
+                            .insert(Velocity(direction * PROJECTILE_SPEED))
+                            .insert(Transform::from_translation(translation))
+                            .insert(Replicated::Projectile);
+                    }
+                }
+                PlayerCommand::ChooseUpgrade { choice } => {
+                    let player_upgrades = lobby
+                        .players
+                        .get(&client_id)
+                        .and_then(|player_entity| upgrades.get_mut(*player_entity).ok());
+
+                    if let Some((mut experience, mut weapons)) = player_upgrades {
+                        // Ignore choices that were never offered, the client might be out of date
+                        if choice >= experience.offered_upgrades.len() {
+                            continue;
+                        }
+
+                        let upgrade = experience.offered_upgrades.swap_remove(choice);
+                        experience.offered_upgrades.clear();
+                        experience.pending_level_ups -= 1;
+
+                        weapons.apply_upgrade(&upgrade, &weapon_definitions);
+                    }
+                }
+                PlayerCommand::SetReady { ready } => {
+                    // Readying up only means something between matches
+                    if !matches!(current_match.state, MatchState::Lobby | MatchState::GameOver) {
+                        continue;
+                    }
+
+                    if ready {
+                        lobby.ready.insert(client_id);
+                    } else {
+                        lobby.ready.remove(&client_id);
+                    }
+                    server.broadcast_message(ServerChannel::ServerMessages.id(), lobby.message());
+                }
+                PlayerCommand::SetDifficulty { difficulty } => {
+                    if lobby.host != Some(client_id) {
+                        continue;
+                    }
+
+                    lobby.difficulty = difficulty;
+                    server.broadcast_message(ServerChannel::ServerMessages.id(), lobby.message());
+                }
             }
         }
         while let Some(message) = server.receive_message(client_id, ClientChannel::Input.id()) {
-            let input: PlayerInput = bincode::deserialize(&message).unwrap();
+            let input = match guard.accept_input(&message, tick.0) {
+                Some(input) => input,
+                None => continue,
+            };
+
             client_ticks.0.insert(client_id, input.most_recent_tick);
-            if let Some(player_entity) = lobby.players.get(&client_id) {
-                commands.entity(*player_entity).insert(input);
+            let input_queue = lobby
+                .players
+                .get(&client_id)
+                .and_then(|player_entity| input_queues.get_mut(*player_entity).ok());
+
+            if let Some(mut input_queue) = input_queue {
+                input_queue.inputs.push_back(input);
+            }
+        }
+    }
+}
+
+/// Forgives old strikes, and tells kicked clients why before disconnecting them
+fn client_guard_system(
+    time: Res<Time>,
+    mut server: ResMut<RenetServer>,
+    mut guards: ResMut<ClientGuards>,
+    lobby: Res<ServerLobby>,
+) {
+    let seconds = time.delta_seconds();
+    for (client_id, guard) in guards.0.iter_mut() {
+        guard.update(seconds);
+
+        if let Some((reason, timer)) = guard.kicked.as_mut() {
+            if timer.elapsed().is_zero() {
+                let username = lobby.usernames.get(client_id).cloned().unwrap_or_else(|| client_id.to_string());
+                println!("Kicking player {}: {}", username, reason);
+                let message = bincode::serialize(&ServerMessages::Kicked { reason: reason.clone() }).unwrap();
+                server.send_message(*client_id, ServerChannel::ServerMessages.id(), message);
+            }
+
+            timer.tick(time.delta());
+            if timer.finished() {
+                server.disconnect(*client_id);
             }
         }
     }
 }
 
-#[allow(clippy::type_complexity)]
+/// Tells every client about the entities that came close to their player or went away since the last
+/// time, and sends them a frame with the positions of the ones they know about
+#[allow(clippy::too_many_arguments)]
 fn server_network_sync(
-    mut tick: ResMut<NetworkTick>,
+    tick: Res<NetworkTick>,
     mut server: ResMut<RenetServer>,
-    networked_entities: Query<(Entity, &Transform), With<Player>>,
+    client_ticks: Res<ClientTicks>,
+    mut history: ResMut<SnapshotHistory>,
+    mut replication_sets: ResMut<ReplicationSets>,
+    players: Query<(Entity, &Player, &Transform, &InputQueue)>,
+    replicated: Query<(Entity, &Transform, &Replicated)>,
 ) {
-    let mut frame = NetworkFrame::default();
-    for (entity, transform) in networked_entities.iter() {
-        frame.entities.entities.push(entity);
-        frame.entities.translations.push(transform.translation.into());
+    let mut grid = SpatialGrid::new(GRID_CELL_SIZE);
+    for (entity, transform, _) in replicated.iter() {
+        grid.insert(entity, transform.translation.truncate());
     }
 
-    frame.tick = tick.0;
-    tick.0 += 1;
-    let sync_message = bincode::serialize(&frame).unwrap();
+    // Forget about clients that left
+    let connected = |id: &u64| players.iter().any(|(_, player, _, _)| player.id == *id);
+    history.0.retain(|id, _| connected(id));
+    replication_sets.0.retain(|id, _| connected(id));
 
-    server.broadcast_message(ServerChannel::NetworkFrame.id(), sync_message);
-}
+    let frame_tick = tick.0;
+    for (_, player, player_transform, input_queue) in players.iter() {
+        let center = player_transform.translation.truncate();
+        let replication_set = replication_sets.0.entry(player.id).or_default();
+
+        replication_set.retain(|entity, kind| {
+            let relevant = replicated
+                .get(*entity)
+                .is_ok_and(|(_, transform, _)| transform.translation.truncate().distance(center) <= RELEVANCE_RADIUS + RELEVANCE_MARGIN);
+            if !relevant {
+                let message = bincode::serialize(&kind.despawn_message(*entity)).unwrap();
+                server.send_message(player.id, ServerChannel::ServerMessages.id(), message);
+            }
+            relevant
+        });
+
+        for entity in grid.query(center, RELEVANCE_RADIUS) {
+            if replication_set.contains_key(&entity) {
+                continue;
+            }
+
+            let (_, transform, kind) = replicated.get(entity).unwrap();
+            let message = bincode::serialize(&kind.spawn_message(entity, transform.translation)).unwrap();
+            server.send_message(player.id, ServerChannel::ServerMessages.id(), message);
+            replication_set.insert(entity, *kind);
+        }
+
+        let mut snapshot = WorldSnapshot::default();
+        for (entity, _, transform, _) in players.iter() {
+            snapshot.insert(entity, transform.translation);
+        }
+        for entity in replication_set.keys() {
+            let (_, transform, _) = replicated.get(*entity).unwrap();
+            snapshot.insert(*entity, transform.translation);
+        }
 
-// TODO
-fn move_players_system(time: Res<Time>, mut query: Query<(&mut Transform, &PlayerInput, &mut Velocity, &MaxSpeed), With<Player>>) {
-    for (mut transform, input, mut velocity, max_speed) in query.iter_mut() {
-        let x = (input.right as i8 - input.left as i8) as f32;
-        let y = (input.up as i8 - input.down as i8) as f32;
-        let direction = Vec2::new(x, y).normalize_or_zero();
-        velocity.0.x = (direction.x * PLAYER_SPEED) * time.delta().as_secs_f32();
-        velocity.0.y = (direction.y * PLAYER_SPEED) * time.delta().as_secs_f32();
+        // Encode the frame against the last one the client told us they received.
+        // If that one is too old we don't have it anymore, and they get a full frame instead.
+        let client_history = history.0.entry(player.id).or_default();
+        let baseline = client_ticks
+            .0
+            .get(&player.id)
+            .copied()
+            .flatten()
+            .and_then(|acked_tick| client_history.iter().find(|(tick, _)| *tick == acked_tick))
+            .map(|(tick, snapshot)| (*tick, snapshot));
+
+        let frames = match snapshot.encode(frame_tick, baseline, input_queue.last_processed) {
+            Ok(frames) => frames,
+            // Not sending anything keeps this snapshot out of the history, so it's never used as a baseline
+            Err(err) => {
+                println!("Could not send a frame to {}: {}", player.id, err);
+                continue;
+            }
+        };
+        for frame in frames {
+            server.send_message(player.id, ServerChannel::NetworkFrame.id(), frame.to_bytes());
+        }
 
-        transform.translation.x += velocity.0.x;
-        transform.translation.y += velocity.0.y;
+        client_history.push_back((frame_tick, snapshot));
+        if client_history.len() > SNAPSHOT_HISTORY {
+            client_history.pop_front();
+        }
     }
 }
 
-pub struct ServerPlugins;
+/// Moves the match along: starts the countdown once everybody is ready, starts the match when it's done,
+/// and ends it when all players are dead at the same time
+fn match_system(
+    mut current_match: ResMut<Match>,
+    mut lobby: ResMut<ServerLobby>,
+    mut wave_director: ResMut<WaveDirector>,
+    mut server: ResMut<RenetServer>,
+    mut reset_world: EventWriter<ResetWorld>,
+    players: Query<(&Player, Option<&Dead>)>,
+) {
+    // Everybody left, so the next players start over in the lobby
+    if players.is_empty() {
+        if !matches!(current_match.state, MatchState::Lobby) {
+            current_match.state = MatchState::Lobby;
+            lobby.ready.clear();
+        }
+        return;
+    }
 
-impl PluginGroup for ServerPlugins {
-    fn build(&mut self, group: &mut PluginGroupBuilder) {
-        group.add(bevy::log::LogPlugin::default());
-        group.add(bevy::core::CorePlugin::default());
-        group.add(bevy::time::TimePlugin::default());
-        group.add(TransformPlugin::default());
-        group.add(HierarchyPlugin::default());
-        group.add(bevy::diagnostic::DiagnosticsPlugin::default());
-        group.add(bevy::app::ScheduleRunnerPlugin::default());
+    let previous_phase = std::mem::discriminant(&current_match.state);
+    match &mut current_match.state {
+        MatchState::Lobby | MatchState::GameOver => {
+            if players.iter().all(|(player, _)| lobby.ready.contains(&player.id)) {
+                lobby.ready.clear();
+                server.broadcast_message(ServerChannel::ServerMessages.id(), lobby.message());
+
+                current_match.state = MatchState::Countdown(Timer::from_seconds(COUNTDOWN_TIME, false));
+                current_match.stats.clear();
+                *wave_director = WaveDirector {
+                    difficulty: lobby.difficulty,
+                    ..Default::default()
+                };
+                reset_world.send(ResetWorld);
+            }
+        }
+        MatchState::Countdown(timer) => {
+            timer.tick(TICK_DURATION);
+            if timer.finished() {
+                current_match.state = MatchState::Running;
+            }
+        }
+        MatchState::Running => {
+            for (player, dead) in players.iter() {
+                let stats = current_match.stats.entry(player.id).or_default();
+                if dead.is_none() {
+                    stats.survival_ticks += 1;
+                }
+            }
+
+            if players.iter().all(|(_, dead)| dead.is_some()) {
+                current_match.state = MatchState::GameOver;
+            }
+        }
     }
-}
\ No newline at end of file
+
+    if std::mem::discriminant(&current_match.state) != previous_phase {
+        server.broadcast_message(ServerChannel::ServerMessages.id(), current_match.phase_message());
+    }
+}
+
+fn match_running(In(should_run): In<ShouldRun>, current_match: Res<Match>) -> ShouldRun {
+    match (&current_match.state, should_run) {
+        (MatchState::Running, _) => should_run,
+        // The match can start during one of several ticks in a frame, so keep checking until the last one
+        (_, ShouldRun::YesAndCheckAgain | ShouldRun::NoAndCheckAgain) => ShouldRun::NoAndCheckAgain,
+        (_, ShouldRun::Yes | ShouldRun::No) => ShouldRun::No,
+    }
+}
+
+/// Clears out everything the last match left behind, and brings every player back to the start
+fn reset_world_system(
+    mut commands: Commands,
+    mut reset_world: EventReader<ResetWorld>,
+    mut server: ResMut<RenetServer>,
+    weapon_definitions: Res<WeaponDefinitions>,
+    classes: Res<CharacterClasses>,
+    replicated: Query<Entity, With<Replicated>>,
+    mut players: Query<(Entity, &Player, &PlayerClass, &mut Transform, &mut Health, &mut Weapons, &mut Experience, &mut MagnetRadius)>,
+) {
+    if reset_world.iter().count() == 0 {
+        return;
+    }
+
+    for entity in replicated.iter() {
+        commands.entity(entity).despawn();
+    }
+
+    for (entity, player, class, mut transform, mut health, mut weapons, mut experience, mut magnet_radius) in players.iter_mut() {
+        transform.translation = Vec3::new(0.0, 0.51, 0.0);
+        health.current = health.max;
+        let starting_weapon = classes.get(class.0).starting_weapon;
+        *weapons = Weapons(vec![EquippedWeapon::new(starting_weapon, &weapon_definitions)]);
+        *experience = Experience::default();
+        *magnet_radius = MagnetRadius::default();
+        commands.entity(entity).remove::<Dead>();
+
+        let message = bincode::serialize(&ServerMessages::PlayerRespawned {
+            id: player.id,
+            translation: [transform.translation.x, transform.translation.y],
+        })
+            .unwrap();
+        server.broadcast_message(ServerChannel::ServerMessages.id(), message);
+    }
+}
+
+/// The server side of the client's network overlay, printed for every client
+fn network_stats_system(
+    time: Res<Time>,
+    mut stats_log: ResMut<NetworkStatsLog>,
+    server: Res<RenetServer>,
+    lobby: Res<ServerLobby>,
+    tick: Res<NetworkTick>,
+    client_ticks: Res<ClientTicks>,
+) {
+    stats_log.0.tick(time.delta());
+    if !stats_log.0.just_finished() {
+        return;
+    }
+
+    for client_id in server.clients_id() {
+        let network_info = match server.network_info(client_id) {
+            Some(network_info) => network_info,
+            None => continue,
+        };
+        let username = lobby.usernames.get(&client_id).cloned().unwrap_or_else(|| client_id.to_string());
+        let ticks_behind = match client_ticks.0.get(&client_id).copied().flatten() {
+            Some(acked_tick) => (tick.0.saturating_sub(acked_tick)).to_string(),
+            None => "-".to_string(),
+        };
+
+        println!(
+            "{}: rtt {:.0} ms, packet loss {:.1}%, sent {:.1} kbps, received {:.1} kbps, {} ticks behind",
+            username,
+            network_info.rtt,
+            network_info.packet_loss * 100.0,
+            network_info.sent_bandwidth_kbps,
+            network_info.received_bandwidth_kbps,
+            ticks_behind
+        );
+    }
+}
+
+fn frame_start_system(mut frame_times: ResMut<FrameTimes>) {
+    frame_times.frame_started = Instant::now();
+}
+
+/// Tells everybody how long frames take and how many ticks are simulated, which falls below the tick rate
+/// once the server can't keep up
+fn server_stats_system(
+    time: Res<Time>,
+    mut frame_times: ResMut<FrameTimes>,
+    mut server: ResMut<RenetServer>,
+    tick: Res<NetworkTick>,
+    players: Query<(), With<Player>>,
+    enemies: Query<(), With<Enemy>>,
+) {
+    let frame_time = frame_times.frame_started.elapsed();
+    frame_times.total += frame_time;
+    frame_times.max = frame_times.max.max(frame_time);
+    frame_times.frames += 1;
+
+    frame_times.report.tick(time.delta());
+    if !frame_times.report.just_finished() {
+        return;
+    }
+
+    let message = ServerMessages::ServerStats {
+        frame_time: frame_times.total.as_secs_f32() * 1000.0 / frame_times.frames as f32,
+        max_frame_time: frame_times.max.as_secs_f32() * 1000.0,
+        ticks_per_second: (tick.0 - frame_times.tick_at_last_report) as f32 / SERVER_STATS_INTERVAL,
+        players: players.iter().count() as u32,
+        enemies: enemies.iter().count() as u32,
+    };
+    let message = bincode::serialize(&message).unwrap();
+    server.broadcast_message(ServerChannel::ServerMessages.id(), message);
+
+    frame_times.total = Duration::ZERO;
+    frame_times.max = Duration::ZERO;
+    frame_times.frames = 0;
+    frame_times.tick_at_last_report = tick.0;
+}
+
+fn advance_tick_system(mut tick: ResMut<NetworkTick>) {
+    tick.0 += 1;
+}
+
+/// Applies one input per tick, since clients send them at the tick rate. When inputs pile up because
+/// of jitter, the extra ones get applied right away so the player doesn't lag behind.
+fn move_players_system(
+    arena: Res<Arena>,
+    mut query: Query<(&mut Transform, &MaxSpeed, &mut InputQueue, Option<&Dead>), With<Player>>,
+) {
+    for (mut transform, max_speed, mut input_queue, dead) in query.iter_mut() {
+        let backlog = input_queue.inputs.len().saturating_sub(MAX_BUFFERED_INPUTS);
+        for _ in 0..=backlog {
+            let input = match input_queue.inputs.pop_front() {
+                Some(input) => input,
+                None => break,
+            };
+
+            // Dead players don't move, but their inputs still count as processed
+            if dead.is_none() {
+                move_player(&mut transform.translation, &input, max_speed.0, &arena);
+            }
+            input_queue.last_processed = Some(input.sequence);
+        }
+    }
+}
+
+/// Projectiles fly in a straight line until they hit a wall
+fn move_projectiles_system(
+    arena: Res<Arena>,
+    mut spent: ResMut<SpentProjectiles>,
+    mut query: Query<(Entity, &mut Transform, &Velocity), With<Projectile>>,
+) {
+    for (entity, mut transform, velocity) in query.iter_mut() {
+        transform.translation += velocity.0.extend(0.0) * TICK_DURATION.as_secs_f32();
+        if arena.is_blocked(transform.translation.truncate(), PROJECTILE_RADIUS) {
+            spent.0.insert(entity);
+        }
+    }
+}
+
+fn projectile_lifetime_system(
+    mut spent: ResMut<SpentProjectiles>,
+    mut projectiles: Query<(Entity, &mut Projectile)>,
+) {
+    for (entity, mut projectile) in projectiles.iter_mut() {
+        projectile.duration.tick(TICK_DURATION);
+        if projectile.duration.finished() {
+            spent.0.insert(entity);
+        }
+    }
+}
+
+fn despawn_spent_projectiles_system(mut commands: Commands, mut spent: ResMut<SpentProjectiles>) {
+    for entity in spent.0.drain() {
+        commands.entity(entity).despawn();
+    }
+}
+
+fn wave_director_system(
+    mut commands: Commands,
+    arena: Res<Arena>,
+    mut wave_director: ResMut<WaveDirector>,
+    players: Query<&Transform, With<Player>>,
+    enemies: Query<Entity, With<Enemy>>,
+) {
+    // Nobody to chase, so there's no point in sending out waves
+    if players.is_empty() {
+        return;
+    }
+
+    wave_director.timer.tick(TICK_DURATION);
+    if !wave_director.timer.just_finished() {
+        return;
+    }
+
+    let mut rng = rand::thread_rng();
+    let mut enemy_count = enemies.iter().count();
+    for player_transform in players.iter() {
+        for _ in 0..wave_director.enemies_per_player() {
+            if enemy_count >= MAX_ENEMIES {
+                break;
+            }
+
+            let kind = wave_director.pick_kind(&mut rng);
+            // Enemies can't spawn inside walls or outside the arena, near the edge it can take a few tries
+            let translation = (0..SPAWN_ATTEMPTS)
+                .map(|_| {
+                    let angle = rng.gen_range(0.0..std::f32::consts::TAU);
+                    let distance = rng.gen_range(SPAWN_RADIUS_MIN..SPAWN_RADIUS_MAX);
+                    player_transform.translation + Vec3::new(angle.cos(), angle.sin(), 0.0) * distance
+                })
+                .find(|translation| !arena.is_blocked(translation.truncate(), kind.size() / 2.0));
+            let translation = match translation {
+                Some(translation) => translation,
+                None => continue,
+            };
+
+            commands
+                .spawn()
+                .insert(Enemy { kind })
+                .insert(Health::new(kind.health() * wave_director.difficulty.enemy_health()))
+                .insert(LastHitBy::default())
+                .insert(Transform::from_translation(translation))
+                .insert(Replicated::Enemy(kind));
+            enemy_count += 1;
+        }
+    }
+
+    wave_director.wave += 1;
+}
+
+/// Enemies walk straight towards the nearest player that is still alive, sliding along any wall in the way
+fn enemy_chase_system(
+    arena: Res<Arena>,
+    players: Query<&Transform, (With<Player>, Without<Enemy>, Without<Dead>)>,
+    mut enemies: Query<(&mut Transform, &Enemy), Without<Player>>,
+) {
+    for (mut transform, enemy) in enemies.iter_mut() {
+        let nearest_player = players
+            .iter()
+            .map(|player_transform| player_transform.translation)
+            .min_by(|a, b| {
+                let distance_a = a.distance_squared(transform.translation);
+                let distance_b = b.distance_squared(transform.translation);
+                distance_a.partial_cmp(&distance_b).unwrap()
+            });
+
+        if let Some(target) = nearest_player {
+            let direction = (target - transform.translation).truncate().normalize_or_zero();
+            let delta = direction * enemy.kind.speed() * TICK_DURATION.as_secs_f32();
+            arena.move_and_collide(&mut transform.translation, delta, enemy.kind.size() / 2.0);
+        }
+    }
+}
+
+/// Enemies hurt every player they are touching
+fn enemy_contact_damage_system(
+    mut players: Query<(&Transform, &mut Health), (With<Player>, Without<Dead>)>,
+    enemies: Query<(&Transform, &Enemy)>,
+) {
+    for (player_transform, mut health) in players.iter_mut() {
+        for (enemy_transform, enemy) in enemies.iter() {
+            let touch_distance = PLAYER_RADIUS + enemy.kind.size() / 2.0;
+            if player_transform.translation.distance(enemy_transform.translation) < touch_distance {
+                health.current = (health.current - enemy.kind.contact_damage() * TICK_DURATION.as_secs_f32()).max(0.0);
+            }
+        }
+    }
+}
+
+/// Projectiles hurt the first enemy they hit and disappear
+fn projectile_hit_system(
+    mut spent: ResMut<SpentProjectiles>,
+    projectiles: Query<(Entity, &Transform, &Projectile)>,
+    mut enemies: Query<(&Transform, &Enemy, &mut Health, &mut LastHitBy)>,
+) {
+    for (projectile_entity, projectile_transform, projectile) in projectiles.iter() {
+        // Already hit a wall or something else this tick
+        if spent.0.contains(&projectile_entity) {
+            continue;
+        }
+
+        for (enemy_transform, enemy, mut health, mut last_hit_by) in enemies.iter_mut() {
+            // Enemies that are already dead can't stop projectiles anymore
+            if health.current <= 0.0 {
+                continue;
+            }
+
+            let hit_distance = PROJECTILE_RADIUS + enemy.kind.size() / 2.0;
+            if projectile_transform.translation.distance(enemy_transform.translation) < hit_distance {
+                health.current = (health.current - projectile.damage).max(0.0);
+                last_hit_by.0 = Some(projectile.owner);
+                spent.0.insert(projectile_entity);
+                break;
+            }
+        }
+    }
+}
+
+/// Dead enemies leave an XP gem behind
+fn enemy_death_system(
+    mut commands: Commands,
+    mut current_match: ResMut<Match>,
+    enemies: Query<(Entity, &Enemy, &Health, &Transform, &LastHitBy)>,
+) {
+    let mut rng = rand::thread_rng();
+    for (entity, enemy, health, transform, last_hit_by) in enemies.iter() {
+        if health.current <= 0.0 {
+            commands.entity(entity).despawn();
+
+            if let Some(killer) = last_hit_by.0 {
+                current_match.stats.entry(killer).or_default().kills += 1;
+            }
+
+            let mut drops = vec![Pickup::XpGem { xp: enemy.kind.xp() }];
+            if rng.gen_bool(HEALTH_POTION_CHANCE) {
+                drops.push(Pickup::HealthPotion { heal: HEALTH_POTION_HEAL });
+            }
+            if rng.gen_bool(MAGNET_CHANCE) {
+                drops.push(Pickup::Magnet);
+            }
+            if enemy.kind == EnemyKind::Ghoul && rng.gen_bool(CHEST_CHANCE) {
+                drops.push(Pickup::Chest);
+            }
+
+            // Spread out a little, so they don't all lie on top of each other
+            for (i, pickup) in drops.into_iter().enumerate() {
+                let offset = Vec3::new(i as f32 * 12.0, 0.0, 0.0);
+                commands
+                    .spawn()
+                    .insert(pickup)
+                    .insert(Transform::from_translation(transform.translation + offset))
+                    .insert(Replicated::Pickup(pickup));
+            }
+        }
+    }
+}
+
+fn player_death_system(
+    mut commands: Commands,
+    mut server: ResMut<RenetServer>,
+    players: Query<(Entity, &Player, &Health), Without<Dead>>,
+) {
+    for (entity, player, health) in players.iter() {
+        if health.current <= 0.0 {
+            println!("Player {} died.", player.id);
+            commands.entity(entity).insert(Dead {
+                respawn: Timer::from_seconds(RESPAWN_TIME, false),
+            });
+
+            let message = bincode::serialize(&ServerMessages::PlayerDied { id: player.id }).unwrap();
+            server.broadcast_message(ServerChannel::ServerMessages.id(), message);
+        }
+    }
+}
+
+/// Dead players come back with full health next to one of the players that survived
+fn player_respawn_system(
+    mut commands: Commands,
+    mut server: ResMut<RenetServer>,
+    mut dead_players: Query<(Entity, &Player, &mut Dead, &mut Health, &mut Transform)>,
+    alive_players: Query<&Transform, (With<Player>, Without<Dead>)>,
+) {
+    for (entity, player, mut dead, mut health, mut transform) in dead_players.iter_mut() {
+        dead.respawn.tick(TICK_DURATION);
+        if !dead.respawn.finished() {
+            continue;
+        }
+
+        health.current = health.max;
+        transform.translation = alive_players
+            .iter()
+            .next()
+            .map(|alive_transform| alive_transform.translation)
+            .unwrap_or(Vec3::new(0.0, 0.51, 0.0));
+        commands.entity(entity).remove::<Dead>();
+
+        let message = bincode::serialize(&ServerMessages::PlayerRespawned {
+            id: player.id,
+            translation: [transform.translation.x, transform.translation.y],
+        })
+            .unwrap();
+        server.broadcast_message(ServerChannel::ServerMessages.id(), message);
+    }
+}
+
+/// Tells the clients about changes in health. Contact damage changes health a tiny bit every frame,
+/// so we only send an update once the health bar would actually change.
+fn sync_player_health_system(
+    mut server: ResMut<RenetServer>,
+    mut players: Query<(&Player, &Health, &mut SyncedHealth), Changed<Health>>,
+) {
+    for (player, health, mut synced_health) in players.iter_mut() {
+        if health.current.ceil() == synced_health.0.ceil() {
+            continue;
+        }
+        synced_health.0 = health.current;
+
+        let message = bincode::serialize(&ServerMessages::PlayerHealth {
+            id: player.id,
+            current: health.current,
+            max: health.max,
+        })
+            .unwrap();
+        server.broadcast_message(ServerChannel::ServerMessages.id(), message);
+    }
+}
+
+/// Fires every weapon that is off cooldown
+fn weapon_system(
+    mut commands: Commands,
+    weapon_definitions: Res<WeaponDefinitions>,
+    mut server: ResMut<RenetServer>,
+    mut players: Query<(Entity, &Player, &Transform, &mut Weapons), Without<Dead>>,
+    mut enemies: Query<(&Transform, &Enemy, &mut Health, &mut LastHitBy)>,
+) {
+    for (player_entity, player, player_transform, mut weapons) in players.iter_mut() {
+        let origin = player_transform.translation;
+
+        for equipped in weapons.0.iter_mut() {
+            equipped.cooldown.tick(TICK_DURATION);
+            if !equipped.cooldown.just_finished() {
+                continue;
+            }
+
+            let stats = weapon_definitions.level(equipped.weapon, equipped.level);
+            match weapon_definitions.0[equipped.weapon].behaviour {
+                WeaponBehaviour::Projectile => {
+                    // Aim at the nearest enemies that are in range
+                    let mut targets: Vec<Vec3> = enemies
+                        .iter()
+                        .map(|(enemy_transform, _, _, _)| enemy_transform.translation)
+                        .filter(|translation| translation.distance(origin) <= stats.range)
+                        .collect();
+                    targets.sort_by(|a, b| a.distance_squared(origin).partial_cmp(&b.distance_squared(origin)).unwrap());
+
+                    for target in targets.into_iter().take(stats.amount as usize) {
+                        let direction = (target - origin).truncate().normalize_or_zero();
+                        commands
+                            .spawn()
+                            .insert(Projectile {
+                                owner: player.id,
+                                duration: Timer::from_seconds(stats.range / stats.speed, false),
+                                damage: stats.damage,
+                            })
+                            .insert(Velocity(direction * stats.speed))
+                            .insert(Transform::from_translation(origin))
+                            .insert(Replicated::Projectile);
+                    }
+                }
+                WeaponBehaviour::Orbit => {
+                    // Spread the blades evenly around the player
+                    for i in 0..stats.amount {
+                        let angle = std::f32::consts::TAU * i as f32 / stats.amount as f32;
+                        let translation = origin + Vec3::new(angle.cos(), angle.sin(), 0.0) * stats.range;
+                        commands
+                            .spawn()
+                            .insert(OrbitingBlade {
+                                owner: player_entity,
+                                owner_id: player.id,
+                                angle,
+                                radius: stats.range,
+                                // Blades travel along the circle at the weapon's speed
+                                angular_speed: stats.speed / stats.range,
+                                damage: stats.damage,
+                                duration: Timer::from_seconds(stats.cooldown * BLADE_UPTIME, false),
+                                hit_timer: Timer::from_seconds(BLADE_HIT_INTERVAL, true),
+                            })
+                            .insert(Transform::from_translation(translation))
+                            .insert(Replicated::Blade);
+                    }
+                }
+                WeaponBehaviour::Pulse => {
+                    for (enemy_transform, _, mut health, mut last_hit_by) in enemies.iter_mut() {
+                        if enemy_transform.translation.distance(origin) <= stats.range {
+                            health.current = (health.current - stats.damage).max(0.0);
+                            last_hit_by.0 = Some(player.id);
+                        }
+                    }
+
+                    let message = bincode::serialize(&ServerMessages::Pulse {
+                        translation: [origin.x, origin.y],
+                        radius: stats.range,
+                    })
+                        .unwrap();
+                    server.broadcast_message(ServerChannel::ServerMessages.id(), message);
+                }
+            }
+        }
+    }
+}
+
+/// Moves blades around their owner, and removes them once they are done spinning or their owner is gone
+fn orbit_blades_system(
+    mut commands: Commands,
+    players: Query<&Transform, (With<Player>, Without<Dead>, Without<OrbitingBlade>)>,
+    mut blades: Query<(Entity, &mut OrbitingBlade, &mut Transform)>,
+) {
+    for (entity, mut blade, mut transform) in blades.iter_mut() {
+        blade.duration.tick(TICK_DURATION);
+
+        match players.get(blade.owner) {
+            Ok(owner_transform) if !blade.duration.finished() => {
+                blade.angle += blade.angular_speed * TICK_DURATION.as_secs_f32();
+                transform.translation = owner_transform.translation
+                    + Vec3::new(blade.angle.cos(), blade.angle.sin(), 0.0) * blade.radius;
+            }
+            _ => commands.entity(entity).despawn(),
+        }
+    }
+}
+
+fn blade_hit_system(
+    mut blades: Query<(&Transform, &mut OrbitingBlade)>,
+    mut enemies: Query<(&Transform, &Enemy, &mut Health, &mut LastHitBy), Without<OrbitingBlade>>,
+) {
+    for (blade_transform, mut blade) in blades.iter_mut() {
+        blade.hit_timer.tick(TICK_DURATION);
+        if !blade.hit_timer.just_finished() {
+            continue;
+        }
+
+        for (enemy_transform, enemy, mut health, mut last_hit_by) in enemies.iter_mut() {
+            let hit_distance = BLADE_RADIUS + enemy.kind.size() / 2.0;
+            if blade_transform.translation.distance(enemy_transform.translation) < hit_distance {
+                health.current = (health.current - blade.damage).max(0.0);
+                last_hit_by.0 = Some(blade.owner_id);
+            }
+        }
+    }
+}
+
+/// Pulls pickups towards the nearest player that has them within their magnet radius. Chests are too heavy.
+fn magnet_system(
+    arena: Res<Arena>,
+    players: Query<(&Transform, &MagnetRadius), (With<Player>, Without<Dead>)>,
+    mut pickups: Query<(&mut Transform, &Pickup), Without<Player>>,
+) {
+    for (mut pickup_transform, pickup) in pickups.iter_mut() {
+        if *pickup == Pickup::Chest {
+            continue;
+        }
+
+        let target = players
+            .iter()
+            .map(|(player_transform, magnet_radius)| (player_transform.translation, magnet_radius.0))
+            .filter(|(translation, magnet_radius)| translation.distance(pickup_transform.translation) < *magnet_radius)
+            .map(|(translation, _)| translation)
+            .min_by(|a, b| {
+                let distance_a = a.distance_squared(pickup_transform.translation);
+                let distance_b = b.distance_squared(pickup_transform.translation);
+                distance_a.partial_cmp(&distance_b).unwrap()
+            });
+
+        if let Some(target) = target {
+            let direction = (target - pickup_transform.translation).truncate().normalize_or_zero();
+            let delta = direction * PICKUP_PULL_SPEED * TICK_DURATION.as_secs_f32();
+            arena.move_and_collide(&mut pickup_transform.translation, delta, 2.0);
+        }
+    }
+}
+
+/// Applies whatever a player walked over
+fn collect_pickups_system(
+    mut commands: Commands,
+    mut server: ResMut<RenetServer>,
+    weapon_definitions: Res<WeaponDefinitions>,
+    mut players: Query<(&Player, &Transform, &mut Experience, &mut Health, &mut MagnetRadius, &mut Weapons), Without<Dead>>,
+    pickups: Query<(Entity, &Transform, &Pickup)>,
+) {
+    let mut rng = rand::thread_rng();
+    for (pickup_entity, pickup_transform, pickup) in pickups.iter() {
+        // Potions are left for whoever needs them, even when a player at full health is standing on them too
+        let collector = players.iter_mut().find(|(_, player_transform, _, health, ..)| {
+            player_transform.translation.distance(pickup_transform.translation) < PICKUP_RADIUS
+                && (!matches!(pickup, Pickup::HealthPotion { .. }) || health.current < health.max)
+        });
+
+        let (player, _, mut experience, mut health, mut magnet_radius, mut weapons) = match collector {
+            Some(collector) => collector,
+            None => continue,
+        };
+
+        match *pickup {
+            Pickup::XpGem { xp } => {
+                experience.xp += xp;
+                while experience.xp >= xp_needed(experience.level) {
+                    experience.xp -= xp_needed(experience.level);
+                    experience.level += 1;
+                    experience.pending_level_ups += 1;
+                }
+            }
+            Pickup::HealthPotion { heal } => {
+                health.current = (health.current + heal).min(health.max);
+            }
+            Pickup::Magnet => {
+                magnet_radius.0 = (magnet_radius.0 + MAGNET_BONUS).min(MAX_MAGNET_RADIUS);
+            }
+            Pickup::Chest => {
+                // With everything maxed out the chest is simply empty
+                if let Some(upgrade) = weapons.available_upgrades(&weapon_definitions).choose(&mut rng) {
+                    weapons.apply_upgrade(upgrade, &weapon_definitions);
+                    // An offer for the same weapon is out of date now, so make a fresh one
+                    if experience.offered_upgrades.iter().any(|offered| offered.weapon == upgrade.weapon) {
+                        experience.offered_upgrades.clear();
+                    }
+
+                    let message = bincode::serialize(&ServerMessages::UpgradeGranted { upgrade: upgrade.clone() }).unwrap();
+                    server.send_message(player.id, ServerChannel::ServerMessages.id(), message);
+                }
+            }
+        }
+
+        commands.entity(pickup_entity).despawn();
+    }
+}
+
+/// Offers three random upgrades to players that have leveled up and aren't already choosing
+fn offer_upgrades_system(
+    weapon_definitions: Res<WeaponDefinitions>,
+    mut server: ResMut<RenetServer>,
+    mut players: Query<(&Player, &Weapons, &mut Experience)>,
+) {
+    let mut rng = rand::thread_rng();
+    for (player, weapons, mut experience) in players.iter_mut() {
+        if experience.pending_level_ups == 0 || !experience.offered_upgrades.is_empty() {
+            continue;
+        }
+
+        let mut choices = weapons.available_upgrades(&weapon_definitions);
+        choices.shuffle(&mut rng);
+        choices.truncate(3);
+
+        // Everything is maxed out, so there's nothing left to offer
+        if choices.is_empty() {
+            experience.pending_level_ups = 0;
+            continue;
+        }
+
+        experience.offered_upgrades = choices.clone();
+        let message = bincode::serialize(&ServerMessages::LevelUp { choices }).unwrap();
+        server.send_message(player.id, ServerChannel::ServerMessages.id(), message);
+    }
+}
+
+fn sync_experience_system(
+    mut server: ResMut<RenetServer>,
+    players: Query<(&Player, &Experience), Changed<Experience>>,
+) {
+    for (player, experience) in players.iter() {
+        let message = bincode::serialize(&ServerMessages::PlayerExperience {
+            xp: experience.xp,
+            xp_needed: xp_needed(experience.level),
+            level: experience.level,
+        })
+            .unwrap();
+        server.send_message(player.id, ServerChannel::ServerMessages.id(), message);
+    }
+}
+
+pub struct ServerPlugins;
+
+impl PluginGroup for ServerPlugins {
+    fn build(&mut self, group: &mut PluginGroupBuilder) {
+        group.add(bevy::log::LogPlugin::default());
+        group.add(bevy::core::CorePlugin::default());
+        group.add(bevy::time::TimePlugin::default());
+        group.add(TransformPlugin::default());
+        group.add(HierarchyPlugin::default());
+        group.add(bevy::diagnostic::DiagnosticsPlugin::default());
+        group.add(bevy::app::ScheduleRunnerPlugin::default());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::*;
+
+    fn input(sequence: u32, most_recent_tick: Option<u32>) -> Vec<u8> {
+        bincode::serialize(&PlayerInput { most_recent_tick, sequence, ..Default::default() }).unwrap()
+    }
+
+    fn kick_reason(guard: &ClientGuard) -> Option<&str> {
+        guard.kicked.as_ref().map(|(reason, _)| reason.as_str())
+    }
+
+    /// Sends a bad message with `send` until the client is kicked, with the rate limit out of the way, and
+    /// returns why they were kicked. `send` returns whether the message was accepted.
+    fn strike_out(guard: &mut ClientGuard, mut send: impl FnMut(&mut ClientGuard) -> bool) -> Option<String> {
+        for strike in 1..=MAX_STRIKES as usize {
+            guard.inputs.refill(1.0);
+            guard.commands.refill(1.0);
+            assert!(!send(guard), "the message was accepted");
+            assert_eq!(guard.strikes, strike as f32);
+            assert_eq!(guard.kicked.is_some(), strike == MAX_STRIKES as usize);
+        }
+        kick_reason(guard).map(str::to_string)
+    }
+
+    #[test]
+    fn accepts_inputs_in_order() {
+        let mut guard = ClientGuard::default();
+        for sequence in 0..10 {
+            let input = guard.accept_input(&input(sequence, Some(sequence)), 10).unwrap();
+            assert_eq!(input.sequence, sequence);
+        }
+        assert_eq!(guard.strikes, 0.0);
+    }
+
+    #[test]
+    fn kicks_for_truncated_inputs() {
+        let message = input(1, Some(1));
+        let mut guard = ClientGuard::default();
+        let reason = strike_out(&mut guard, |guard| guard.accept_input(&message[..message.len() - 1], 10).is_some());
+        assert_eq!(reason.as_deref(), Some("Sent invalid inputs"));
+    }
+
+    #[test]
+    fn kicks_for_random_messages() {
+        fn random_message(rng: &mut StdRng) -> Vec<u8> {
+            let len = rng.gen_range(0..32);
+            (0..len).map(|_| rng.gen()).collect()
+        }
+
+        let mut rng = StdRng::seed_from_u64(45);
+
+        let mut guard = ClientGuard::default();
+        let reason = strike_out(&mut guard, |guard| loop {
+            // Some random bytes happen to be a valid input, those aren't what this is about
+            let message = random_message(&mut rng);
+            if bincode::deserialize::<PlayerInput>(&message).is_err() {
+                break guard.accept_input(&message, 10).is_some();
+            }
+        });
+        assert_eq!(reason.as_deref(), Some("Sent invalid inputs"));
+
+        let mut guard = ClientGuard::default();
+        let reason = strike_out(&mut guard, |guard| loop {
+            let message = random_message(&mut rng);
+            if bincode::deserialize::<PlayerCommand>(&message).is_err() {
+                break guard.accept_command(&message).is_some();
+            }
+        });
+        assert_eq!(reason.as_deref(), Some("Sent invalid commands"));
+    }
+
+    #[test]
+    fn kicks_for_attacks_at_non_finite_positions() {
+        for cast_at in [Vec3::new(f32::NAN, 0.0, 0.0), Vec3::new(0.0, f32::INFINITY, 0.0), Vec3::splat(f32::NEG_INFINITY)] {
+            let message = bincode::serialize(&PlayerCommand::BasicAttack { cast_at }).unwrap();
+            let mut guard = ClientGuard::default();
+            let reason = strike_out(&mut guard, |guard| guard.accept_command(&message).is_some());
+            assert_eq!(reason.as_deref(), Some("Sent invalid commands"));
+        }
+
+        let message = bincode::serialize(&PlayerCommand::BasicAttack { cast_at: Vec3::new(1.0, 2.0, 0.0) }).unwrap();
+        assert!(ClientGuard::default().accept_command(&message).is_some());
+    }
+
+    #[test]
+    fn kicks_for_inputs_out_of_order() {
+        let mut guard = ClientGuard::default();
+        assert!(guard.accept_input(&input(100, None), 10).is_some());
+
+        let mut attempts = 0;
+        let reason = strike_out(&mut guard, |guard| {
+            // Repeats of the last input count too
+            attempts += 1;
+            let sequence = if attempts % 2 == 0 { 100 } else { 100 - attempts };
+            guard.accept_input(&input(sequence, None), 10).is_some()
+        });
+        assert_eq!(reason.as_deref(), Some("Sent inputs out of order"));
+    }
+
+    #[test]
+    fn kicks_for_acknowledging_future_ticks() {
+        let mut guard = ClientGuard::default();
+        assert!(guard.accept_input(&input(0, Some(10)), 10).is_some());
+
+        let mut sequence = 0;
+        let reason = strike_out(&mut guard, |guard| {
+            sequence += 1;
+            guard.accept_input(&input(sequence, Some(11)), 10).is_some()
+        });
+        assert_eq!(reason.as_deref(), Some("Acknowledged frames that were never sent"));
+        // Rejected inputs don't move the sequence along
+        assert_eq!(guard.last_sequence, Some(0));
+    }
+
+    #[test]
+    fn kicks_for_flooding() {
+        let mut guard = ClientGuard::default();
+        let burst = guard.inputs.tokens as u32;
+        let (mut sequence, mut accepted) = (0, 0);
+        while guard.kicked.is_none() {
+            sequence += 1;
+            if guard.accept_input(&input(sequence, None), 10).is_some() {
+                accepted += 1;
+            }
+        }
+        assert_eq!(kick_reason(&guard), Some("Sent too many inputs"));
+        assert_eq!(accepted, burst);
+        // It takes a second's worth of extra inputs to make a strike
+        assert!(sequence - burst >= (MAX_STRIKES - 1.0) as u32 * INPUTS_PER_SECOND as u32);
+
+        // Once kicked, nothing gets through anymore
+        guard.inputs.refill(1.0);
+        assert!(guard.accept_input(&input(sequence + 1, None), 10).is_none());
+    }
+
+    #[test]
+    fn keeps_clients_that_catch_up_after_a_stall() {
+        let mut guard = ClientGuard::default();
+        let mut sequence = 0;
+        let mut send = |guard: &mut ClientGuard, inputs: u32| {
+            for _ in 0..inputs {
+                sequence += 1;
+                guard.accept_input(&input(sequence, None), 10);
+            }
+        };
+
+        for _ in 0..30 {
+            // Two seconds of one input per tick
+            for _ in 0..2 * TICK_RATE {
+                guard.update(1.0 / TICK_RATE as f32);
+                send(&mut guard, 1);
+            }
+            // Then the client stalls for a second and catches up on every tick it missed at once,
+            // and what was lost on the way in comes in right after
+            guard.update(1.0);
+            send(&mut guard, TICK_RATE as u32);
+            guard.update(1.0 / TICK_RATE as f32);
+            send(&mut guard, TICK_RATE as u32 / 2);
+        }
+
+        assert!(guard.kicked.is_none());
+        assert!(guard.strikes < MAX_STRIKES / 2.0);
+    }
+
+    #[test]
+    fn reads_names_from_any_user_data() {
+        let user_data = vampire_surviors_clone::auth::player_user_data("Ana", 1).unwrap();
+        assert_eq!(name_from_user_data(&user_data), "Ana");
+        assert_eq!(class_from_user_data(&user_data), 1);
+
+        // Too long, and a multi-byte character cut in half at the end
+        let mut user_data = [0xFFu8; NETCODE_USER_DATA_BYTES];
+        user_data[0..8].copy_from_slice(&u64::MAX.to_le_bytes());
+        for (i, byte) in "é".repeat(MAX_USERNAME_BYTES).bytes().take(MAX_USERNAME_BYTES).enumerate() {
+            user_data[8 + i] = byte;
+        }
+        let name = name_from_user_data(&user_data);
+        assert!(name.starts_with("éé"));
+        assert!(name.ends_with(char::REPLACEMENT_CHARACTER));
+    }
+
+    /// Inputs player `index` sends at `tick`, the same on every run. Every few ticks an input arrives a tick
+    /// late together with the next one, like it does with jitter, so catching up is part of the test too.
+    fn scripted_inputs(index: u32, tick: u32) -> Vec<PlayerInput> {
+        let input = |sequence: u32| {
+            let step = (sequence / 20 + index) % 8;
+            PlayerInput {
+                most_recent_tick: tick.checked_sub(1),
+                sequence,
+                up: matches!(step, 0 | 1 | 7),
+                down: matches!(step, 3 | 4 | 5),
+                left: matches!(step, 5 | 6 | 7),
+                right: matches!(step, 1 | 2 | 3),
+            }
+        };
+        match tick % 7 {
+            0 => vec![],
+            1 if tick > 1 => vec![input(tick - 1), input(tick)],
+            _ => vec![input(tick)],
+        }
+    }
+
+    fn test_app(state: MatchState) -> App {
+        let mut app = App::new();
+        // The clock is moved along by `simulate` instead of the `TimePlugin`
+        app.add_plugin(bevy::core::CorePlugin::default());
+        app.insert_resource(Time::default());
+        app.insert_resource(ServerLobby::default());
+        app.insert_resource(NetworkTick(0));
+        app.insert_resource(Match { state, stats: HashMap::new() });
+        app.insert_resource(SpentProjectiles::default());
+        app.add_event::<ResetWorld>();
+        app.insert_resource(WaveDirector::default());
+        let weapon_definitions = WeaponDefinitions::load(WEAPONS_PATH).unwrap();
+        app.insert_resource(CharacterClasses::load(CLASSES_PATH, &weapon_definitions).unwrap());
+        app.insert_resource(weapon_definitions);
+        app.insert_resource(Arena::load(ARENA_PATH).unwrap());
+        app.insert_resource(new_renet_server(AMOUNT_PLAYERS, "127.0.0.1:0".parse().unwrap(), false, None));
+
+        add_tick_systems(&mut app);
+        app
+    }
+
+    fn spawn_player(app: &mut App, id: u64, translation: Vec3) {
+        let weapon_definitions = app.world.resource::<WeaponDefinitions>();
+        let class = app.world.resource::<CharacterClasses>().get(0);
+        let (speed, health, weapons) = (class.speed, class.health, Weapons(vec![EquippedWeapon::new(class.starting_weapon, weapon_definitions)]));
+        app.world
+            .spawn()
+            .insert(Player { id })
+            .insert(InputQueue::default())
+            .insert(Transform::from_translation(translation))
+            .insert(PlayerClass(0))
+            .insert(MaxSpeed(speed))
+            .insert(Health::new(health))
+            .insert(weapons)
+            .insert(Experience::default())
+            .insert(MagnetRadius::default());
+    }
+
+    fn spawn_enemy(app: &mut App, kind: EnemyKind, translation: Vec3) {
+        // Nothing dies, so no random drops end up in the world
+        app.world
+            .spawn()
+            .insert(Enemy { kind })
+            .insert(Health::new(1.0e9))
+            .insert(LastHitBy::default())
+            .insert(Transform::from_translation(translation))
+            .insert(Replicated::Enemy(kind));
+    }
+
+    /// Ticks that pass between two frames, like on a server that sometimes falls behind and catches up
+    const UNEVEN_FRAMES: [u32; 6] = [1, 0, 2, 1, 3, 1];
+
+    /// Runs `ticks` ticks with the scripted inputs, moving the clock along by `frames` ticks every frame,
+    /// and returns where every entity ended up
+    fn simulate(app: &mut App, ticks: u32, frames: &[u32]) -> Vec<(Entity, Vec3)> {
+        let mut now = Instant::now();
+        app.world.resource_mut::<Time>().update_with_instant(now);
+
+        let mut frames = frames.iter().cycle();
+        let mut fed = 0;
+        while app.world.resource::<NetworkTick>().0 < ticks {
+            // Once every input is in, the clock keeps going until the last ticks have run
+            let steps = if fed < ticks { (*frames.next().unwrap()).min(ticks - fed) } else { 1 };
+            for tick in fed + 1..=(fed + steps).min(ticks) {
+                let mut players = app.world.query::<(&Player, &mut InputQueue)>();
+                for (player, mut input_queue) in players.iter_mut(&mut app.world) {
+                    input_queue.inputs.extend(scripted_inputs(player.id as u32, tick));
+                }
+            }
+            fed = (fed + steps).min(ticks);
+
+            now += TICK_DURATION * steps;
+            app.world.resource_mut::<Time>().update_with_instant(now);
+            app.update();
+        }
+        assert_eq!(app.world.resource::<NetworkTick>().0, ticks);
+
+        let mut transforms = app.world.query::<(Entity, &Transform)>();
+        let mut translations: Vec<(Entity, Vec3)> = transforms.iter(&app.world).map(|(entity, transform)| (entity, transform.translation)).collect();
+        translations.sort_by_key(|(entity, _)| *entity);
+        translations
+    }
+
+    fn lobby() -> App {
+        let mut app = test_app(MatchState::Lobby);
+        for id in 0..4 {
+            spawn_player(&mut app, id, Vec3::new(id as f32 * 40.0 - 60.0, 0.51, 0.0));
+        }
+        app
+    }
+
+    fn running_match() -> App {
+        let mut app = test_app(MatchState::Running);
+        for id in 0..4 {
+            spawn_player(&mut app, id, Vec3::new(id as f32 * 40.0 - 60.0, 0.51, 0.0));
+        }
+        for i in 0..30 {
+            let kind = [EnemyKind::Bat, EnemyKind::Zombie, EnemyKind::Ghoul][i % 3];
+            spawn_enemy(&mut app, kind, Vec3::new(i as f32 * 23.0 - 345.0, 180.0 - i as f32 * 11.0, 0.0));
+        }
+        app
+    }
+
+    /// Ticks the match systems have run for, going by the wave timer
+    fn match_ticks(app: &App) -> u32 {
+        (app.world.resource::<WaveDirector>().timer.elapsed().as_nanos() / TICK_DURATION.as_nanos()) as u32
+    }
+
+    #[test]
+    fn same_inputs_move_players_the_same() {
+        for frames in [&[1][..], &UNEVEN_FRAMES] {
+            let first = simulate(&mut lobby(), 600, frames);
+            let second = simulate(&mut lobby(), 600, frames);
+            assert_eq!(first.len(), 4);
+            assert_eq!(first, second);
+        }
+    }
+
+    #[test]
+    fn same_inputs_play_out_the_same() {
+        // Stays below a wave interval, since waves spawn enemies at random
+        let ticks = (WAVE_INTERVAL * TICK_RATE as f32) as u32 - 1;
+        for frames in [&[1][..], &UNEVEN_FRAMES] {
+            let (mut first_app, mut second_app) = (running_match(), running_match());
+            let first = simulate(&mut first_app, ticks, frames);
+            let second = simulate(&mut second_app, ticks, frames);
+            assert!(first.len() >= 34);
+            assert_eq!(first, second);
+
+            // Every tick ran the match systems exactly once
+            assert_eq!(match_ticks(&first_app), ticks);
+            assert_eq!(match_ticks(&second_app), ticks);
+        }
+    }
+
+    #[test]
+    fn match_starts_on_the_same_tick_however_frames_fall() {
+        let ticks = (COUNTDOWN_TIME * TICK_RATE as f32) as u32 + 60;
+        let started_match = |frames: &[u32]| {
+            let mut app = lobby();
+            app.world.resource_mut::<ServerLobby>().ready.extend(0..4);
+            simulate(&mut app, ticks, frames);
+            assert!(matches!(app.world.resource::<Match>().state, MatchState::Running));
+            match_ticks(&app)
+        };
+
+        let expected = started_match(&[1]);
+        assert!(expected > 0);
+        assert_eq!(started_match(&UNEVEN_FRAMES), expected);
+        assert_eq!(started_match(&[3, 0, 0, 4]), expected);
+    }
+}
@@ -0,0 +1,103 @@
+use std::fs::File;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use clap::{CommandFactory, Parser};
+use clap::error::ErrorKind;
+
+use vampire_surviors_clone::{PORT, PRIVATE_KEY, VERSION};
+use vampire_surviors_clone::auth::{IssuedToken, MAX_USERNAME_BYTES, TOKEN_EXPIRE_SECONDS};
+use vampire_surviors_clone::classes::{CharacterClasses, CLASSES_PATH};
+use vampire_surviors_clone::config::{parse_host, validate_port};
+use vampire_surviors_clone::weapons::{WeaponDefinitions, WEAPONS_PATH};
+
+/// Options for issuing a token, taken from the command line, then environment variables
+#[derive(Debug, Parser)]
+#[command(name = "token", version = VERSION, about = "Issues connect tokens for the server, signed with the same private key it uses")]
+struct TokenArgs {
+    /// Name shown to the other players
+    username: String,
+    /// IP address of the server, it has to be the exact address the server binds to [default: 127.0.0.1]
+    #[arg(long, env = "SERVER_HOST")]
+    host: Option<String>,
+    /// Port of the server [default: 5000]
+    #[arg(long, env = "SERVER_PORT")]
+    port: Option<u16>,
+    /// File to write the token to [default: <username>.token]
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    /// Class to play as, clients connecting with the token skip choosing one [default: the first one]
+    #[arg(long)]
+    class: Option<String>,
+}
+
+/// The token options once everything has been filled in and checked
+struct TokenOptions {
+    username: String,
+    server_addr: SocketAddr,
+    output: PathBuf,
+    class: usize,
+}
+
+impl TokenArgs {
+    /// Fills in whatever wasn't given from the defaults, exiting with an error when any of it doesn't make sense
+    fn options(self, classes: &CharacterClasses) -> TokenOptions {
+        self.resolve(classes).unwrap_or_else(|message| TokenArgs::command().error(ErrorKind::ValueValidation, message).exit())
+    }
+
+    fn resolve(self, classes: &CharacterClasses) -> Result<TokenOptions, String> {
+        if self.username.trim().is_empty() {
+            return Err("The username can't be empty".to_string());
+        }
+        if self.username.len() > MAX_USERNAME_BYTES {
+            return Err(format!("The username can be at most {} bytes long", MAX_USERNAME_BYTES));
+        }
+
+        let port = validate_port(self.port.unwrap_or(PORT))?;
+        let host = parse_host(self.host.as_deref().unwrap_or("127.0.0.1"))?;
+
+        // The class ends up in the user data, which is part of the signed token, so it's picked here
+        let class = match self.class {
+            Some(name) => classes.0.iter().position(|class| class.name.eq_ignore_ascii_case(&name)).ok_or_else(|| {
+                let names: Vec<&str> = classes.0.iter().map(|class| class.name.as_str()).collect();
+                format!("Unknown class {}, pick one of {}", name, names.join(", "))
+            })?,
+            None => 0,
+        };
+
+        let output = self.output.unwrap_or_else(|| PathBuf::from(format!("{}.token", self.username)));
+        Ok(TokenOptions {
+            username: self.username,
+            server_addr: SocketAddr::new(host, port),
+            output,
+            class,
+        })
+    }
+}
+
+fn main() {
+    let args = TokenArgs::parse();
+    let weapon_definitions = WeaponDefinitions::load(WEAPONS_PATH).expect("Could not load the weapon definitions");
+    let classes = CharacterClasses::load(CLASSES_PATH, &weapon_definitions).expect("Could not load the classes");
+    let TokenOptions { username, server_addr, output, class } = args.options(&classes);
+
+    let token = match IssuedToken::generate(&username, class, server_addr, PRIVATE_KEY) {
+        Ok(token) => token,
+        Err(err) => {
+            println!("Could not generate the connect token: {}", err);
+            return;
+        }
+    };
+    let mut file = File::create(&output).expect("Could not create the token file");
+    token.write(&mut file).expect("Could not write the token file");
+
+    println!(
+        "Issued a token for {} the {} (client id {}) connecting to {}, valid for {} minutes: {}",
+        username,
+        classes.0[class].name,
+        token.client_id,
+        server_addr,
+        TOKEN_EXPIRE_SECONDS / 60,
+        output.display()
+    );
+}
@@ -0,0 +1,52 @@
+use std::fs;
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+
+use crate::weapons::WeaponDefinitions;
+
+pub const CLASSES_PATH: &str = "assets/data/classes.json";
+
+/// What a player picks before joining, deciding how they start out
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CharacterClass {
+    pub name: String,
+    pub description: String,
+    pub speed: f32,
+    pub health: f32,
+    /// Index into the `WeaponDefinitions`
+    pub starting_weapon: usize,
+    pub sprite: String,
+    /// Hex color the sprite is tinted with, so classes can share a sprite
+    pub tint: String,
+}
+
+/// Every class in the game, loaded from `CLASSES_PATH`. Classes are referred to by their index in here,
+/// which is also what clients send in their user data.
+#[derive(Debug, Clone)]
+pub struct CharacterClasses(pub Vec<CharacterClass>);
+
+impl CharacterClasses {
+    pub fn load(path: &str, weapon_definitions: &WeaponDefinitions) -> anyhow::Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        let classes: Vec<CharacterClass> = serde_json::from_str(&contents)?;
+        if classes.is_empty() {
+            bail!("There has to be at least one class");
+        }
+        // The class index has to fit in a single byte of user data
+        if classes.len() > u8::MAX as usize + 1 {
+            bail!("There can't be more than {} classes", u8::MAX as usize + 1);
+        }
+        for class in classes.iter() {
+            if class.starting_weapon >= weapon_definitions.0.len() {
+                bail!("{} starts with weapon {}, which doesn't exist", class.name, class.starting_weapon);
+            }
+        }
+        Ok(Self(classes))
+    }
+
+    /// The class at `index`, or the first one if there is no such class
+    pub fn get(&self, index: usize) -> &CharacterClass {
+        self.0.get(index).unwrap_or(&self.0[0])
+    }
+}
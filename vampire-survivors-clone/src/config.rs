@@ -0,0 +1,28 @@
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::Path;
+
+use anyhow::Context;
+use serde::de::DeserializeOwned;
+
+/// Reads a JSON config file for one of the binaries. Its fields are named like the long command line flags,
+/// and flags or environment variables take priority over anything in it.
+pub fn read_config_file<T: DeserializeOwned>(path: &Path) -> anyhow::Result<T> {
+    let contents = fs::read_to_string(path).with_context(|| format!("Could not read {}", path.display()))?;
+    serde_json::from_str(&contents).with_context(|| format!("Could not parse {}", path.display()))
+}
+
+/// Turns a host into the address to bind to or connect to. Only IP addresses are accepted, apart from localhost.
+pub fn parse_host(host: &str) -> Result<IpAddr, String> {
+    if host == "localhost" {
+        return Ok(Ipv4Addr::LOCALHOST.into());
+    }
+    host.parse().map_err(|_| format!("'{}' is not an IP address", host))
+}
+
+pub fn validate_port(port: u16) -> Result<u16, String> {
+    match port {
+        0 => Err("The port can't be 0".to_string()),
+        port => Ok(port),
+    }
+}
@@ -5,7 +5,19 @@ use bevy::prelude::*;
 use bevy_renet::renet::{ChannelConfig, NETCODE_KEY_BYTES, ReliableChannelConfig, RenetConnectionConfig, UnreliableChannelConfig};
 use serde::{Deserialize, Serialize};
 
-pub const PORT: i32 = 5000;
+use crate::arena::Arena;
+use crate::weapons::UpgradeChoice;
+
+pub mod arena;
+pub mod auth;
+pub mod classes;
+pub mod config;
+pub mod netsim;
+pub mod snapshot;
+pub mod spatial;
+pub mod weapons;
+
+pub const PORT: u16 = 5000;
 pub const AMOUNT_PLAYERS: usize = 4;
 
 pub const PRIVATE_KEY: &[u8; NETCODE_KEY_BYTES] = b"an example very very secret key.";
@@ -22,6 +34,8 @@ pub struct Player {
 #[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, Component)]
 pub struct PlayerInput {
     pub most_recent_tick: Option<u32>,
+    /// Counts up with every input the client sends, so the server can tell it which ones it has applied
+    pub sequence: u32,
     pub up: bool,
     pub down: bool,
     pub left: bool,
@@ -34,11 +48,189 @@ pub struct Velocity(pub Vec2);
 #[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, Component)]
 pub struct MaxSpeed(pub f32);
 
-pub const PLAYER_SPEED: f32 = 100.0;
+/// The server simulates this many ticks per second, no matter how fast it is
+pub const TICK_RATE: u64 = 60;
+/// Every input moves the player for this many seconds, on both the client and the server.
+/// The server applies one input per player every tick.
+pub const INPUT_TIMESTEP: f32 = 1.0 / TICK_RATE as f32;
+
+/// Moves a player for a single input, stopping at the walls of the arena. The client predicts its own
+/// movement with this, so it has to be the exact same code the server runs.
+pub fn move_player(translation: &mut Vec3, input: &PlayerInput, speed: f32, arena: &Arena) {
+    let x = (input.right as i8 - input.left as i8) as f32;
+    let y = (input.up as i8 - input.down as i8) as f32;
+    let direction = Vec2::new(x, y).normalize_or_zero();
+
+    arena.move_and_collide(translation, direction * speed * INPUT_TIMESTEP, PLAYER_RADIUS);
+}
+
+pub const PROJECTILE_SPEED: f32 = 300.0;
+pub const PROJECTILE_DAMAGE: f32 = 10.0;
+/// Projectiles are drawn as 16x16 sprites, but the bullet itself is a lot smaller
+pub const PROJECTILE_RADIUS: f32 = 4.0;
+/// How long a projectile flies before it disappears, in seconds
+pub const PROJECTILE_LIFETIME: f32 = 1.5;
+
+/// Seconds a dead player has to spectate before they respawn
+pub const RESPAWN_TIME: f32 = 10.0;
+/// Players are drawn as 32x32 sprites, this is the radius used for collisions
+pub const PLAYER_RADIUS: f32 = 16.0;
+
+#[derive(Debug, Clone, Copy, Component)]
+pub struct Health {
+    pub current: f32,
+    pub max: f32,
+}
+
+impl Health {
+    pub fn new(max: f32) -> Self {
+        Self { current: max, max }
+    }
+}
+
+/// The different kinds of enemies. Later waves bring in the tougher kinds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EnemyKind {
+    Bat,
+    Zombie,
+    Ghoul,
+}
+
+impl EnemyKind {
+    pub fn speed(&self) -> f32 {
+        match self {
+            Self::Bat => 70.0,
+            Self::Zombie => 40.0,
+            Self::Ghoul => 60.0,
+        }
+    }
+
+    pub fn health(&self) -> f32 {
+        match self {
+            Self::Bat => 10.0,
+            Self::Zombie => 40.0,
+            Self::Ghoul => 100.0,
+        }
+    }
+
+    /// Damage per second dealt to a player the enemy is touching
+    pub fn contact_damage(&self) -> f32 {
+        match self {
+            Self::Bat => 5.0,
+            Self::Zombie => 10.0,
+            Self::Ghoul => 20.0,
+        }
+    }
+
+    /// How much XP the gem dropped by this enemy is worth
+    pub fn xp(&self) -> u32 {
+        match self {
+            Self::Bat => 1,
+            Self::Zombie => 3,
+            Self::Ghoul => 10,
+        }
+    }
+
+    /// Enemies are squares, this is the length of their sides
+    pub fn size(&self) -> f32 {
+        match self {
+            Self::Bat => 16.0,
+            Self::Zombie => 28.0,
+            Self::Ghoul => 40.0,
+        }
+    }
+}
+
+#[derive(Debug, Component)]
+pub struct Enemy {
+    pub kind: EnemyKind,
+}
+
+#[derive(Debug, Component)]
+pub struct Projectile {
+    pub owner: u64,
+    pub duration: Timer,
+    pub damage: f32,
+}
+
+/// Players pick up anything they walk within this distance of
+pub const PICKUP_RADIUS: f32 = 24.0;
+
+/// Something lying on the ground for players to walk over
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Component)]
+pub enum Pickup {
+    XpGem { xp: u32 },
+    HealthPotion { heal: f32 },
+    /// Makes the radius pickups are pulled in from bigger
+    Magnet,
+    /// Grants a random upgrade on the spot
+    Chest,
+}
+
+/// Picked by the host in the lobby, makes the waves bigger and the enemies tougher
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Difficulty {
+    Easy,
+    Normal,
+    Hard,
+}
+
+impl Difficulty {
+    pub const ALL: [Difficulty; 3] = [Difficulty::Easy, Difficulty::Normal, Difficulty::Hard];
+
+    /// Multiplies the amount of enemies in a wave
+    pub fn enemy_amount(&self) -> f32 {
+        match self {
+            Self::Easy => 0.5,
+            Self::Normal => 1.0,
+            Self::Hard => 2.0,
+        }
+    }
+
+    /// Multiplies the health of every enemy
+    pub fn enemy_health(&self) -> f32 {
+        match self {
+            Self::Easy => 0.75,
+            Self::Normal => 1.0,
+            Self::Hard => 1.5,
+        }
+    }
+}
+
+impl Default for Difficulty {
+    fn default() -> Self {
+        Self::Normal
+    }
+}
+
+/// Seconds between everybody being ready and the match starting
+pub const COUNTDOWN_TIME: f32 = 3.0;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlayerResult {
+    pub id: u64,
+    /// Seconds the player was alive during the match
+    pub survival_time: f32,
+    pub kills: u32,
+}
+
+/// Where the match is at. Players ready up in the lobby, and again after a game over to play another match.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum MatchPhase {
+    Lobby,
+    Countdown { seconds: f32 },
+    Running,
+    GameOver { results: Vec<PlayerResult> },
+}
 
 #[derive(Debug, Serialize, Deserialize, Component)]
 pub enum PlayerCommand {
     BasicAttack { cast_at: Vec3 },
+    /// Picks one of the upgrades offered by the last `ServerMessages::LevelUp`
+    ChooseUpgrade { choice: usize },
+    SetReady { ready: bool },
+    /// Only the host can change the difficulty
+    SetDifficulty { difficulty: Difficulty },
 }
 
 pub enum ClientChannel {
@@ -53,20 +245,56 @@ pub enum ServerChannel {
 
 #[derive(Debug, Serialize, Deserialize, Component)]
 pub enum ServerMessages {
-    PlayerCreate { entity: Entity, id: u64, translation: [f32; 2] },
+    PlayerCreate { entity: Entity, id: u64, username: String, class: usize, translation: [f32; 2] },
     PlayerRemove { id: u64 },
+    SpawnProjectile { entity: Entity, translation: [f32; 2] },
+    DespawnProjectile { entity: Entity },
+    PlayerHealth { id: u64, current: f32, max: f32 },
+    PlayerDied { id: u64 },
+    PlayerRespawned { id: u64, translation: [f32; 2] },
+    SpawnEnemy { entity: Entity, kind: EnemyKind, translation: [f32; 2] },
+    DespawnEnemy { entity: Entity },
+    SpawnBlade { entity: Entity, translation: [f32; 2] },
+    DespawnBlade { entity: Entity },
+    Pulse { translation: [f32; 2], radius: f32 },
+    SpawnPickup { entity: Entity, pickup: Pickup, translation: [f32; 2] },
+    DespawnPickup { entity: Entity },
+    /// Only sent to the player it is about
+    PlayerExperience { xp: u32, xp_needed: u32, level: u32 },
+    /// Only sent to the player that leveled up
+    LevelUp { choices: Vec<UpgradeChoice> },
+    /// Only sent to the player that opened a chest
+    UpgradeGranted { upgrade: UpgradeChoice },
+    Lobby { host: Option<u64>, difficulty: Difficulty, ready: Vec<u64> },
+    MatchPhase { phase: MatchPhase },
+    /// Sent right before the server disconnects a client that misbehaved
+    Kicked { reason: String },
+    /// Sent to everybody every second, to see whether the server keeps up with the tick rate.
+    /// Frame times are in milliseconds and only count the work, not the time spent waiting for the next frame.
+    ServerStats { frame_time: f32, max_frame_time: f32, ticks_per_second: f32, players: u32, enemies: u32 },
 }
 
 #[derive(Debug, Serialize, Deserialize, Default)]
 pub struct NetworkedEntities {
     pub entities: Vec<Entity>,
-    pub translations: Vec<[f32; 3]>,
+    /// Quantized, see `snapshot::quantize`. Relative to the baseline if the frame has one.
+    pub translations: Vec<[i32; 3]>,
 }
 
+/// Positions of the networked entities at one tick. Big frames are split into several parts,
+/// which all have to arrive before the frame can be used.
 #[derive(Debug, Serialize, Deserialize, Default)]
 pub struct NetworkFrame {
     pub tick: u32,
+    /// The earlier frame this one only contains the changes to, `None` for a full frame
+    pub baseline: Option<u32>,
+    pub part: u8,
+    pub parts: u8,
+    /// Sequence number of the last input the server applied for the receiving player
+    pub last_processed_input: Option<u32>,
     pub entities: NetworkedEntities,
+    /// Entities in the baseline that aren't networked anymore
+    pub removed: Vec<Entity>,
 }
 
 impl ClientChannel {
@@ -137,22 +365,3 @@ pub fn server_connection_config() -> RenetConnectionConfig {
         ..Default::default()
     }
 }
-
-pub fn translate_port(port: &str) -> i32 {
-    port.parse::<i32>().unwrap_or(PORT)
-}
-
-pub fn translate_host<'a>(host: &'a str, default: &'a str) -> &'a str {
-    // 127.0.0.1 if default is not provided
-    let default = if default.is_empty() {
-        "127.0.0.1"
-    } else {
-        default
-    };
-    let host = match host {
-        "localhost" => default,
-        "-" => default,
-        _ => host,
-    };
-    host
-}
\ No newline at end of file
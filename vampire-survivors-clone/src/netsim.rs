@@ -0,0 +1,304 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::collections::hash_map::Entry;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::str::FromStr;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+
+/// How bad the simulated network is. Latency and jitter are added to every packet in both directions,
+/// so the round trip time goes up by twice the latency.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NetworkConditions {
+    pub latency: Duration,
+    /// Every packet is delayed by up to this much more or less than `latency`, which can reorder them
+    pub jitter: Duration,
+    /// Chance between 0 and 1 that a packet is dropped
+    pub loss: f64,
+    /// Chance between 0 and 1 that a packet arrives twice
+    pub duplicate: f64,
+}
+
+impl FromStr for NetworkConditions {
+    type Err = String;
+
+    /// Parses conditions like `latency=100,jitter=20,loss=5,duplicate=1`, in milliseconds and percentages.
+    /// Anything left out stays perfect.
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let mut conditions = NetworkConditions::default();
+        for part in spec.split(',').filter(|part| !part.is_empty()) {
+            let (key, value) = part.split_once('=').ok_or_else(|| format!("Expected key=value, got '{}'", part))?;
+            let value: f64 = value
+                .parse()
+                .ok()
+                .filter(|value: &f64| value.is_finite())
+                .ok_or_else(|| format!("'{}' is not a number", value))?;
+            if value < 0.0 {
+                return Err(format!("{} can't be negative", key));
+            }
+            let milliseconds = |value: f64| Duration::try_from_secs_f64(value / 1000.0).map_err(|_| format!("{} is too long", key));
+
+            match key {
+                "latency" => conditions.latency = milliseconds(value)?,
+                "jitter" => conditions.jitter = milliseconds(value)?,
+                "loss" => conditions.loss = (value / 100.0).min(1.0),
+                "duplicate" => conditions.duplicate = (value / 100.0).min(1.0),
+                _ => return Err(format!("Unknown condition '{}', expected latency, jitter, loss or duplicate", key)),
+            }
+        }
+        Ok(conditions)
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Route {
+    /// From a client to the server, through the upstream socket of that client
+    ToServer(SocketAddr),
+    /// From the server back to a client
+    ToClient(SocketAddr),
+}
+
+struct DelayedPacket {
+    deliver_at: Instant,
+    route: Route,
+    payload: Vec<u8>,
+}
+
+impl PartialEq for DelayedPacket {
+    fn eq(&self, other: &Self) -> bool {
+        self.deliver_at == other.deliver_at
+    }
+}
+
+impl Eq for DelayedPacket {}
+
+impl PartialOrd for DelayedPacket {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for DelayedPacket {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.deliver_at.cmp(&other.deliver_at)
+    }
+}
+
+/// A UDP proxy that passes packets between clients and a server, making the network as bad as the conditions say
+pub struct NetworkSimulator {
+    conditions: NetworkConditions,
+    listen_socket: UdpSocket,
+    server_addr: SocketAddr,
+    /// Every client talks to the server through its own socket, so the server can tell them apart
+    upstream_sockets: HashMap<SocketAddr, UdpSocket>,
+    queue: BinaryHeap<Reverse<DelayedPacket>>,
+}
+
+impl NetworkSimulator {
+    /// Listens for clients on `listen_addr` and forwards their packets to `server_addr`
+    pub fn new(listen_addr: SocketAddr, server_addr: SocketAddr, conditions: NetworkConditions) -> io::Result<Self> {
+        let listen_socket = UdpSocket::bind(listen_addr)?;
+        listen_socket.set_nonblocking(true)?;
+        Ok(Self {
+            conditions,
+            listen_socket,
+            server_addr,
+            upstream_sockets: HashMap::new(),
+            queue: BinaryHeap::new(),
+        })
+    }
+
+    /// Runs the proxy on its own thread until the process exits
+    pub fn spawn(mut self) -> JoinHandle<()> {
+        thread::spawn(move || loop {
+            if let Err(err) = self.update() {
+                println!("Network simulator error: {}", err);
+            }
+            thread::sleep(Duration::from_millis(1));
+        })
+    }
+
+    fn update(&mut self) -> io::Result<()> {
+        let mut buffer = [0u8; 1500];
+
+        while let Some((len, client_addr)) = receive(&self.listen_socket, &mut buffer)? {
+            if let Entry::Vacant(entry) = self.upstream_sockets.entry(client_addr) {
+                let upstream_socket = UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))?;
+                upstream_socket.set_nonblocking(true)?;
+                entry.insert(upstream_socket);
+            }
+            self.schedule(Route::ToServer(client_addr), &buffer[..len]);
+        }
+
+        let mut from_server = Vec::new();
+        for (client_addr, upstream_socket) in self.upstream_sockets.iter() {
+            while let Some((len, _)) = receive(upstream_socket, &mut buffer)? {
+                from_server.push((*client_addr, buffer[..len].to_vec()));
+            }
+        }
+        for (client_addr, payload) in from_server {
+            self.schedule(Route::ToClient(client_addr), &payload);
+        }
+
+        let now = Instant::now();
+        while self.queue.peek().is_some_and(|Reverse(packet)| packet.deliver_at <= now) {
+            let Reverse(packet) = self.queue.pop().unwrap();
+            match packet.route {
+                Route::ToServer(client_addr) => {
+                    if let Some(upstream_socket) = self.upstream_sockets.get(&client_addr) {
+                        upstream_socket.send_to(&packet.payload, self.server_addr)?;
+                    }
+                }
+                Route::ToClient(client_addr) => {
+                    self.listen_socket.send_to(&packet.payload, client_addr)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn schedule(&mut self, route: Route, payload: &[u8]) {
+        let mut rng = rand::thread_rng();
+        if rng.gen_bool(self.conditions.loss) {
+            return;
+        }
+
+        let copies = if rng.gen_bool(self.conditions.duplicate) { 2 } else { 1 };
+        for _ in 0..copies {
+            let jitter = self.conditions.jitter.as_secs_f64() * rng.gen_range(-1.0..=1.0);
+            let delay = (self.conditions.latency.as_secs_f64() + jitter).max(0.0);
+            self.queue.push(Reverse(DelayedPacket {
+                deliver_at: Instant::now() + Duration::from_secs_f64(delay),
+                route,
+                payload: payload.to_vec(),
+            }));
+        }
+    }
+}
+
+/// Receives a packet if there is one waiting
+fn receive(socket: &UdpSocket, buffer: &mut [u8]) -> io::Result<Option<(usize, SocketAddr)>> {
+    match socket.recv_from(buffer) {
+        Ok(received) => Ok(Some(received)),
+        Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(None),
+        // Windows reports ICMP port unreachable from an earlier send as an error here, there's nothing to do about it
+        Err(err) if err.kind() == io::ErrorKind::ConnectionReset => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn local_socket() -> UdpSocket {
+        let socket = UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0)).unwrap();
+        socket.set_nonblocking(true).unwrap();
+        socket
+    }
+
+    /// A client, a server and a simulator in between them, all on loopback
+    fn loopback(conditions: &str) -> (UdpSocket, UdpSocket, NetworkSimulator) {
+        let (client, server) = (local_socket(), local_socket());
+        let simulator = NetworkSimulator::new(
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0),
+            server.local_addr().unwrap(),
+            conditions.parse().unwrap(),
+        )
+        .unwrap();
+        client.connect(simulator.listen_socket.local_addr().unwrap()).unwrap();
+        (client, server, simulator)
+    }
+
+    /// Runs the simulator until `socket` receives a packet or `timeout` passes
+    fn wait_for_packet(simulator: &mut NetworkSimulator, socket: &UdpSocket, timeout: Duration) -> Option<(Vec<u8>, SocketAddr)> {
+        let mut buffer = [0u8; 1500];
+        let started = Instant::now();
+        while started.elapsed() < timeout {
+            simulator.update().unwrap();
+            if let Some((len, from)) = receive(socket, &mut buffer).unwrap() {
+                return Some((buffer[..len].to_vec(), from));
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        None
+    }
+
+    #[test]
+    fn passes_packets_both_ways() {
+        let (client, server, mut simulator) = loopback("");
+        client.send(b"ping").unwrap();
+        let (payload, upstream_addr) = wait_for_packet(&mut simulator, &server, Duration::from_secs(1)).unwrap();
+        assert_eq!(payload, b"ping");
+
+        server.send_to(b"pong", upstream_addr).unwrap();
+        let (payload, _) = wait_for_packet(&mut simulator, &client, Duration::from_secs(1)).unwrap();
+        assert_eq!(payload, b"pong");
+    }
+
+    #[test]
+    fn drops_every_packet_with_full_loss() {
+        let (client, server, mut simulator) = loopback("loss=100");
+        for i in 0..20u8 {
+            client.send(&[i]).unwrap();
+        }
+        assert!(wait_for_packet(&mut simulator, &server, Duration::from_millis(200)).is_none());
+    }
+
+    #[test]
+    fn delays_packets_by_the_latency() {
+        let latency = Duration::from_millis(100);
+        let (client, server, mut simulator) = loopback("latency=100");
+        let sent_at = Instant::now();
+        client.send(b"ping").unwrap();
+
+        assert!(wait_for_packet(&mut simulator, &server, latency / 2).is_none());
+        let (_, upstream_addr) = wait_for_packet(&mut simulator, &server, Duration::from_secs(1)).unwrap();
+        assert!(sent_at.elapsed() >= latency);
+
+        // The way back is delayed just as much
+        let sent_at = Instant::now();
+        server.send_to(b"pong", upstream_addr).unwrap();
+        wait_for_packet(&mut simulator, &client, Duration::from_secs(1)).unwrap();
+        assert!(sent_at.elapsed() >= latency);
+    }
+
+    #[test]
+    fn parses_conditions() {
+        let conditions: NetworkConditions = "latency=100,jitter=20.5,loss=5,duplicate=250".parse().unwrap();
+        assert_eq!(conditions.latency, Duration::from_millis(100));
+        assert_eq!(conditions.jitter, Duration::from_micros(20_500));
+        assert_eq!(conditions.loss, 0.05);
+        assert_eq!(conditions.duplicate, 1.0);
+    }
+
+    #[test]
+    fn left_out_conditions_stay_perfect() {
+        let conditions: NetworkConditions = "loss=10,".parse().unwrap();
+        assert_eq!(conditions.latency, Duration::ZERO);
+        assert_eq!(conditions.jitter, Duration::ZERO);
+        assert_eq!(conditions.loss, 0.1);
+        assert_eq!(conditions.duplicate, 0.0);
+
+        let conditions: NetworkConditions = "".parse().unwrap();
+        assert_eq!(conditions.latency, Duration::ZERO);
+    }
+
+    #[test]
+    fn rejects_unknown_conditions() {
+        assert!("lag=100".parse::<NetworkConditions>().is_err());
+        assert!("latency".parse::<NetworkConditions>().is_err());
+        assert!("latency=100;loss=5".parse::<NetworkConditions>().is_err());
+    }
+
+    #[test]
+    fn rejects_bad_numbers() {
+        for spec in ["latency=", "latency=fast", "loss=-1", "jitter=inf", "latency=NaN", "duplicate=-inf", "latency=1e300"] {
+            assert!(spec.parse::<NetworkConditions>().is_err(), "{} was accepted", spec);
+        }
+    }
+}
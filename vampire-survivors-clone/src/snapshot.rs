@@ -0,0 +1,348 @@
+use std::collections::{HashMap, VecDeque};
+
+use anyhow::bail;
+use bevy::prelude::*;
+use bincode::Options;
+
+use crate::{NetworkedEntities, NetworkFrame};
+
+/// Positions are sent in steps of 1/16th of a unit
+pub const POSITION_SCALE: f32 = 16.0;
+/// Frames are split into parts that stay below this many bytes, so every part fits in a single packet
+pub const FRAME_PART_BUDGET: usize = 1200;
+
+/// Bytes a frame part takes up before any entities are added, with some room to spare
+const FRAME_HEADER_SIZE: usize = 48;
+/// The part count of a frame has to fit in its `parts` byte
+pub const MAX_FRAME_PARTS: usize = u8::MAX as usize;
+/// How many of the last complete frames a `FrameAssembler` keeps around as baselines for the next ones
+const RECEIVED_SNAPSHOT_HISTORY: usize = 64;
+
+/// Frames are encoded with variable length integers, which makes the small numbers in delta frames a lot smaller
+fn frame_options() -> impl Options + Copy {
+    bincode::DefaultOptions::new()
+}
+
+pub fn quantize(translation: Vec3) -> [i32; 3] {
+    [
+        (translation.x * POSITION_SCALE).round() as i32,
+        (translation.y * POSITION_SCALE).round() as i32,
+        (translation.z * POSITION_SCALE).round() as i32,
+    ]
+}
+
+pub fn dequantize(translation: [i32; 3]) -> Vec3 {
+    Vec3::new(translation[0] as f32, translation[1] as f32, translation[2] as f32) / POSITION_SCALE
+}
+
+impl NetworkFrame {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        frame_options().serialize(self).unwrap()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> bincode::Result<Self> {
+        frame_options().deserialize(bytes)
+    }
+}
+
+/// The quantized positions of every networked entity at one tick. The server keeps the ones it sent
+/// and the client the ones it received, so frames only have to contain what changed since one of them.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WorldSnapshot {
+    pub translations: HashMap<Entity, [i32; 3]>,
+}
+
+impl WorldSnapshot {
+    pub fn insert(&mut self, entity: Entity, translation: Vec3) {
+        self.translations.insert(entity, quantize(translation));
+    }
+
+    /// Encodes this snapshot as frame parts, containing only the changes since `baseline` if there is one.
+    /// Fails when there is so much to send that it doesn't fit in `MAX_FRAME_PARTS` parts.
+    pub fn encode(&self, tick: u32, baseline: Option<(u32, &WorldSnapshot)>, last_processed_input: Option<u32>) -> anyhow::Result<Vec<NetworkFrame>> {
+        let mut changes = Vec::new();
+        for (entity, translation) in self.translations.iter() {
+            let change = match baseline.and_then(|(_, baseline)| baseline.translations.get(entity)) {
+                Some(previous) if previous == translation => continue,
+                Some(previous) => [translation[0] - previous[0], translation[1] - previous[1], translation[2] - previous[2]],
+                None => *translation,
+            };
+            changes.push((*entity, change));
+        }
+
+        let removed: Vec<Entity> = match baseline {
+            Some((_, baseline)) => baseline
+                .translations
+                .keys()
+                .filter(|entity| !self.translations.contains_key(entity))
+                .copied()
+                .collect(),
+            None => Vec::new(),
+        };
+
+        let new_part = || NetworkFrame {
+            tick,
+            baseline: baseline.map(|(baseline_tick, _)| baseline_tick),
+            last_processed_input,
+            ..Default::default()
+        };
+
+        // The removed entities go first, then the changes, each starting a new part when the current one is full
+        let options = frame_options();
+        let mut size = FRAME_HEADER_SIZE;
+        let mut frames = vec![new_part()];
+        let mut make_room = |frames: &mut Vec<NetworkFrame>, item_size: usize| {
+            if size + item_size > FRAME_PART_BUDGET {
+                frames.push(new_part());
+                size = FRAME_HEADER_SIZE;
+            }
+            size += item_size;
+        };
+
+        for entity in removed {
+            make_room(&mut frames, options.serialized_size(&entity).unwrap() as usize);
+            frames.last_mut().unwrap().removed.push(entity);
+        }
+        for (entity, change) in changes {
+            make_room(&mut frames, options.serialized_size(&(entity, change)).unwrap() as usize);
+            let frame = frames.last_mut().unwrap();
+            frame.entities.entities.push(entity);
+            frame.entities.translations.push(change);
+        }
+
+        if frames.len() > MAX_FRAME_PARTS {
+            bail!("A frame of {} parts doesn't fit in {} parts", frames.len(), MAX_FRAME_PARTS);
+        }
+        let parts = frames.len() as u8;
+        for (part, frame) in frames.iter_mut().enumerate() {
+            frame.part = part as u8;
+            frame.parts = parts;
+        }
+        Ok(frames)
+    }
+
+    /// Puts a snapshot back together from all parts of a frame, and the baseline they were encoded against
+    pub fn decode(parts: &[NetworkFrame], baseline: Option<&WorldSnapshot>) -> Self {
+        let mut snapshot = baseline.cloned().unwrap_or_default();
+        for frame in parts {
+            for entity in frame.removed.iter() {
+                snapshot.translations.remove(entity);
+            }
+
+            let NetworkedEntities { entities, translations } = &frame.entities;
+            for (entity, change) in entities.iter().zip(translations.iter()) {
+                let translation = match baseline.and_then(|baseline| baseline.translations.get(entity)) {
+                    Some(previous) => [previous[0] + change[0], previous[1] + change[1], previous[2] + change[2]],
+                    None => *change,
+                };
+                snapshot.translations.insert(*entity, translation);
+            }
+        }
+        snapshot
+    }
+}
+
+/// A frame once all of its parts have arrived
+pub struct CompleteFrame<'a> {
+    pub tick: u32,
+    pub last_processed_input: Option<u32>,
+    pub snapshot: &'a WorldSnapshot,
+}
+
+/// Puts incoming frame parts back together, and decodes a frame once every one of its parts is there.
+/// The last complete snapshots are kept around, since later frames are encoded against them.
+#[derive(Debug, Default)]
+pub struct FrameAssembler {
+    /// The snapshots of the last complete frames, oldest first
+    snapshots: VecDeque<(u32, WorldSnapshot)>,
+    /// Parts of frames that haven't fully arrived yet, by tick and then by part
+    partial: HashMap<u32, Vec<Option<NetworkFrame>>>,
+    most_recent_tick: Option<u32>,
+}
+
+impl FrameAssembler {
+    /// The tick of the last complete frame, which is what the server may encode the next frames against
+    pub fn most_recent_tick(&self) -> Option<u32> {
+        self.most_recent_tick
+    }
+
+    /// Takes in one part of a frame, returning the whole frame if that was the last part missing
+    pub fn receive(&mut self, frame: NetworkFrame) -> Option<CompleteFrame<'_>> {
+        if self.most_recent_tick.is_some_and(|tick| tick >= frame.tick) {
+            return None;
+        }
+        if frame.part >= frame.parts {
+            return None;
+        }
+
+        // Wait for the rest of the frame. Parts that arrive twice don't count twice.
+        let tick = frame.tick;
+        let received_parts = self.partial.entry(tick).or_insert_with(|| (0..frame.parts).map(|_| None).collect());
+        if received_parts.len() != frame.parts as usize {
+            return None;
+        }
+        let part = frame.part as usize;
+        if received_parts[part].is_none() {
+            received_parts[part] = Some(frame);
+        }
+        if received_parts.iter().any(Option::is_none) {
+            return None;
+        }
+        let frame_parts: Vec<NetworkFrame> = self.partial.remove(&tick).unwrap().into_iter().flatten().collect();
+
+        let baseline = match frame_parts[0].baseline {
+            Some(baseline_tick) => match self.snapshots.iter().find(|(tick, _)| *tick == baseline_tick) {
+                Some((_, baseline)) => Some(baseline),
+                // We can't make sense of a frame without its baseline, hopefully the next one is based on something we have
+                None => return None,
+            },
+            None => None,
+        };
+        let snapshot = WorldSnapshot::decode(&frame_parts, baseline);
+
+        // Only complete frames count as received, so the server doesn't use a partial one as a baseline
+        self.most_recent_tick = Some(tick);
+        self.partial.retain(|partial_tick, _| *partial_tick > tick);
+        self.snapshots.push_back((tick, snapshot));
+        if self.snapshots.len() > RECEIVED_SNAPSHOT_HISTORY {
+            self.snapshots.pop_front();
+        }
+
+        Some(CompleteFrame {
+            tick,
+            last_processed_input: frame_parts[0].last_processed_input,
+            snapshot: &self.snapshots.back().unwrap().1,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn world(entities: u32, offset: f32) -> WorldSnapshot {
+        let mut snapshot = WorldSnapshot::default();
+        for i in 0..entities {
+            let translation = Vec3::new(i as f32 * 13.7 - 2000.0 + offset, i as f32 * -7.3 + 900.0, 0.0);
+            snapshot.insert(Entity::from_raw(i), translation);
+        }
+        snapshot
+    }
+
+    fn frame_size(frames: &[NetworkFrame]) -> usize {
+        frames.iter().map(|frame| frame.to_bytes().len()).sum()
+    }
+
+    #[test]
+    fn delta_frames_are_smaller_than_full_frames() {
+        let baseline = world(500, 0.0);
+        let mut current = baseline.clone();
+        // A tenth of the entities moved a little since the baseline
+        for i in (0..500).step_by(10) {
+            current.insert(Entity::from_raw(i), dequantize(baseline.translations[&Entity::from_raw(i)]) + Vec3::new(1.5, -0.5, 0.0));
+        }
+
+        let full = frame_size(&current.encode(10, None, None).unwrap());
+        let delta = frame_size(&current.encode(10, Some((5, &baseline)), None).unwrap());
+        let unchanged = frame_size(&baseline.encode(10, Some((5, &baseline)), None).unwrap());
+        println!("500 entities: full frame {} bytes, delta frame {} bytes, unchanged {} bytes", full, delta, unchanged);
+
+        assert!(delta * 10 < full, "delta frame of {} bytes isn't much smaller than the full frame of {} bytes", delta, full);
+        assert!(unchanged < FRAME_HEADER_SIZE);
+    }
+
+    #[test]
+    fn parts_stay_within_budget() {
+        let baseline = world(3000, 0.0);
+        let current = world(2000, 50.0);
+
+        for frames in [current.encode(1, None, None).unwrap(), current.encode(2, Some((1, &baseline)), Some(7)).unwrap()] {
+            assert!(frames.len() > 1);
+            for (part, frame) in frames.iter().enumerate() {
+                assert!(frame.to_bytes().len() <= FRAME_PART_BUDGET, "part {} is {} bytes", part, frame.to_bytes().len());
+                assert_eq!(frame.part as usize, part);
+                assert_eq!(frame.parts as usize, frames.len());
+            }
+        }
+    }
+
+    #[test]
+    fn too_many_parts_is_an_error() {
+        let current = world(MAX_FRAME_PARTS as u32 * 200, 0.0);
+        assert!(current.encode(1, None, None).is_err());
+    }
+
+    #[test]
+    fn decodes_against_baseline() {
+        let baseline = world(300, 0.0);
+        let mut current = world(250, 20.0);
+        // Some entities are new, some are gone, and some didn't move
+        for i in 1000..1020 {
+            current.insert(Entity::from_raw(i), Vec3::new(i as f32, 3.0, 0.0));
+        }
+        for i in 0..10 {
+            current.translations.insert(Entity::from_raw(i), baseline.translations[&Entity::from_raw(i)]);
+        }
+
+        let frames = current.encode(8, Some((4, &baseline)), None).unwrap();
+        assert_eq!(WorldSnapshot::decode(&frames, Some(&baseline)), current);
+
+        let frames = current.encode(8, None, None).unwrap();
+        assert_eq!(WorldSnapshot::decode(&frames, None), current);
+    }
+
+    #[test]
+    fn quantization_error_is_within_a_step() {
+        for i in 0..10_000 {
+            let translation = Vec3::new(i as f32 * 0.731 - 3000.0, i as f32 * -0.1337 + 500.0, (i % 7) as f32 * 0.01);
+            let error = (dequantize(quantize(translation)) - translation).abs();
+            assert!(error.max_element() <= 1.0 / POSITION_SCALE, "{} came back off by {}", translation, error);
+        }
+    }
+
+    #[test]
+    fn assembles_frames_from_all_parts() {
+        let baseline = world(2000, 0.0);
+        let current = world(2000, 30.0);
+        let mut sender = baseline.encode(1, None, None).unwrap();
+
+        let mut assembler = FrameAssembler::default();
+        let last = sender.pop().unwrap();
+        for frame in sender {
+            assert!(assembler.receive(frame).is_none());
+        }
+        assert_eq!(assembler.receive(last).unwrap().snapshot, &baseline);
+
+        // A part arriving twice doesn't stand in for a missing one
+        let mut frames = current.encode(2, Some((1, &baseline)), None).unwrap().into_iter();
+        let first = frames.next().unwrap();
+        let duplicate = NetworkFrame::from_bytes(&first.to_bytes()).unwrap();
+        let rest: Vec<NetworkFrame> = frames.collect();
+        assert!(rest.len() > 1);
+        assert!(assembler.receive(first).is_none());
+        for _ in 0..rest.len() {
+            assert!(assembler.receive(NetworkFrame::from_bytes(&duplicate.to_bytes()).unwrap()).is_none());
+        }
+        assert_eq!(assembler.most_recent_tick(), Some(1));
+
+        // Parts can come in any order
+        for frame in rest.into_iter().rev() {
+            if let Some(complete) = assembler.receive(frame) {
+                assert_eq!(complete.tick, 2);
+                assert_eq!(complete.snapshot, &current);
+            }
+        }
+        assert_eq!(assembler.most_recent_tick(), Some(2));
+    }
+
+    #[test]
+    fn skips_frames_without_their_baseline() {
+        let baseline = world(10, 0.0);
+        let current = world(10, 5.0);
+        let mut assembler = FrameAssembler::default();
+        for frame in current.encode(3, Some((2, &baseline)), None).unwrap() {
+            assert!(assembler.receive(frame).is_none());
+        }
+        assert_eq!(assembler.most_recent_tick(), None);
+    }
+}
@@ -0,0 +1,48 @@
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+
+/// Buckets entities by position, so finding the ones near a point doesn't mean looking at all of them
+#[derive(Debug)]
+pub struct SpatialGrid {
+    cell_size: f32,
+    cells: HashMap<(i32, i32), Vec<(Entity, Vec2)>>,
+}
+
+impl SpatialGrid {
+    pub fn new(cell_size: f32) -> Self {
+        Self {
+            cell_size,
+            cells: HashMap::new(),
+        }
+    }
+
+    fn cell(&self, position: Vec2) -> (i32, i32) {
+        ((position.x / self.cell_size).floor() as i32, (position.y / self.cell_size).floor() as i32)
+    }
+
+    pub fn insert(&mut self, entity: Entity, position: Vec2) {
+        let cell = self.cell(position);
+        self.cells.entry(cell).or_default().push((entity, position));
+    }
+
+    /// Every entity within `radius` of `center`
+    pub fn query(&self, center: Vec2, radius: f32) -> Vec<Entity> {
+        let (min_x, min_y) = self.cell(center - Vec2::splat(radius));
+        let (max_x, max_y) = self.cell(center + Vec2::splat(radius));
+
+        let mut entities = Vec::new();
+        for x in min_x..=max_x {
+            for y in min_y..=max_y {
+                if let Some(cell) = self.cells.get(&(x, y)) {
+                    entities.extend(
+                        cell.iter()
+                            .filter(|(_, position)| position.distance(center) <= radius)
+                            .map(|(entity, _)| *entity),
+                    );
+                }
+            }
+        }
+        entities
+    }
+}
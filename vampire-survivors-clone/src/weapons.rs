@@ -0,0 +1,144 @@
+use std::fs;
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+
+pub const WEAPONS_PATH: &str = "assets/data/weapons.json";
+
+/// How a weapon attacks. The numbers come from the weapon's levels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WeaponBehaviour {
+    /// Fires `amount` projectiles at the nearest enemies within `range`
+    Projectile,
+    /// Spawns `amount` blades circling the player at a distance of `range`, for a while after every cooldown
+    Orbit,
+    /// Damages every enemy within `range` of the player at once
+    Pulse,
+}
+
+/// The stats of a weapon at a single level
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct WeaponLevel {
+    /// Seconds between two attacks
+    pub cooldown: f32,
+    pub damage: f32,
+    pub amount: u32,
+    pub range: f32,
+    /// Units per second the projectiles or blades travel
+    pub speed: f32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WeaponDefinition {
+    pub name: String,
+    pub description: String,
+    pub behaviour: WeaponBehaviour,
+    pub levels: Vec<WeaponLevel>,
+}
+
+/// Every weapon in the game, loaded from `WEAPONS_PATH`. Weapons are referred to by their index in here.
+#[derive(Debug, Clone)]
+pub struct WeaponDefinitions(pub Vec<WeaponDefinition>);
+
+impl WeaponDefinitions {
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        Self::parse(&contents)
+    }
+
+    pub fn parse(contents: &str) -> anyhow::Result<Self> {
+        let definitions: Vec<WeaponDefinition> = serde_json::from_str(contents)?;
+        if definitions.is_empty() {
+            bail!("There has to be at least one weapon");
+        }
+        for definition in definitions.iter() {
+            if definition.levels.is_empty() {
+                bail!("{} has no levels", definition.name);
+            }
+            for (i, level) in definition.levels.iter().enumerate() {
+                // A cooldown of 0 would fire every frame, forever
+                if !level.cooldown.is_finite() || level.cooldown <= 0.0 {
+                    bail!("Level {} of {} needs a cooldown above 0", i + 1, definition.name);
+                }
+                if [level.damage, level.range, level.speed].iter().any(|value| !value.is_finite() || *value < 0.0) {
+                    bail!("Level {} of {} has a damage, range or speed that is negative or not a number", i + 1, definition.name);
+                }
+                // Projectiles live for range / speed seconds and blades turn at speed / range radians per second,
+                // only pulses stand still
+                let moves = matches!(definition.behaviour, WeaponBehaviour::Projectile | WeaponBehaviour::Orbit);
+                if moves && level.speed <= 0.0 {
+                    bail!("Level {} of {} needs a speed above 0", i + 1, definition.name);
+                }
+                if definition.behaviour == WeaponBehaviour::Orbit && level.range <= 0.0 {
+                    bail!("Level {} of {} needs a range above 0", i + 1, definition.name);
+                }
+            }
+        }
+        Ok(Self(definitions))
+    }
+
+    /// The stats of `weapon` at `level`, which has to be at most `max_level`
+    pub fn level(&self, weapon: usize, level: usize) -> &WeaponLevel {
+        &self.0[weapon].levels[level]
+    }
+
+    pub fn max_level(&self, weapon: usize) -> usize {
+        self.0[weapon].levels.len() - 1
+    }
+}
+
+/// An upgrade offered to a player when they level up: either a new weapon, or the next level of one they have
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpgradeChoice {
+    pub weapon: usize,
+    pub name: String,
+    pub description: String,
+    /// The level the weapon will be at after picking this upgrade, starting at 1
+    pub level: usize,
+}
+
+/// XP needed to get from the given level to the next one
+pub fn xp_needed(level: u32) -> u32 {
+    5 + level * 5
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn weapon(behaviour: &str, range: f32, speed: f32) -> String {
+        format!(
+            r#"[{{ "name": "Test", "description": "", "behaviour": "{}",
+                "levels": [{{ "cooldown": 1.0, "damage": 10.0, "amount": 1, "range": {:?}, "speed": {:?} }}] }}]"#,
+            behaviour, range, speed
+        )
+    }
+
+    #[test]
+    fn loads_the_weapons() {
+        let definitions = WeaponDefinitions::load(WEAPONS_PATH).unwrap();
+        assert!(!definitions.0.is_empty());
+    }
+
+    #[test]
+    fn accepts_standing_pulses() {
+        assert!(WeaponDefinitions::parse(&weapon("Pulse", 80.0, 0.0)).is_ok());
+        assert!(WeaponDefinitions::parse(&weapon("Projectile", 300.0, 300.0)).is_ok());
+        assert!(WeaponDefinitions::parse(&weapon("Orbit", 60.0, 180.0)).is_ok());
+    }
+
+    #[test]
+    fn rejects_weapons_that_would_divide_by_zero() {
+        for (behaviour, range, speed) in [("Projectile", 300.0, 0.0), ("Orbit", 60.0, 0.0), ("Orbit", 0.0, 180.0)] {
+            assert!(WeaponDefinitions::parse(&weapon(behaviour, range, speed)).is_err(), "{} with range {} and speed {} was accepted", behaviour, range, speed);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_levels() {
+        assert!(WeaponDefinitions::parse("[]").is_err());
+        assert!(WeaponDefinitions::parse(r#"[{ "name": "Test", "description": "", "behaviour": "Pulse", "levels": [] }]"#).is_err());
+        assert!(WeaponDefinitions::parse(&weapon("Pulse", -1.0, 0.0)).is_err());
+        assert!(WeaponDefinitions::parse(&weapon("Pulse", 80.0, -1.0)).is_err());
+    }
+}